@@ -12,15 +12,92 @@ defmodule Bz2Ex do
 
   ## Options
 
-  - `:block_size` - Integer 1-9. Block size is 100k × this value. Default: `9`.
+  - `:block_size` - Integer 1-9. Block size is 100k × this value. Default: the
+    smallest size that holds the whole input in one block, up to `9`.
   - `:work_factor` - Integer 0-250. Default: `0` (uses internal default of 30).
   - `:small` - Boolean. Use less memory but slower decompression. Default: `false`.
+  - `:multi_stream` - Boolean. Keep decoding concatenated streams (pbzip2 output,
+    `cat a.bz2 b.bz2`) until the input runs out. Default: `true`.
+  - `:max_output_size` - Non-negative integer or `:infinity`. Decompression fails
+    with `:output_limit_exceeded` once the output would grow past this many
+    bytes. Default: `:infinity`.
+  - `:max_ratio` - Positive number or `:infinity`. Same as `:max_output_size`, but
+    relative to the compressed input size. Default: `:infinity`.
+  - `:expected_size` - Non-negative integer. The size the output is expected to
+    have, for example from the caller's own metadata. Decompression allocates
+    exactly this much up front instead of guessing from the input size, and
+    grows the buffer as usual if it turns out to be too small.
+  - `:strict_size` - Boolean. With `:expected_size`, fail with `:size_mismatch`
+    unless the output is exactly that size. Default: `false`.
+  - `:threads` - Positive integer. Number of threads used to decode blocks in
+    parallel. Default: `1`.
+  - `:offset` and `:length` - Non-negative integers. Compress or decompress
+    only `length` bytes of the input starting at `offset`, instead of all of
+    it. Without `:length`, everything from `offset` on is used. A range that
+    does not lie within the input raises `ArgumentError`. Default: the whole
+    input.
+
+  Set both limits when decompressing untrusted input to guard against
+  decompression bombs.
+
+  ## Scheduling
+
+  `compress/2` and `decompress/2` handle inputs of up to 4 KiB right away on
+  the calling process's normal scheduler. For `decompress/2` this only holds
+  for data written with a `:block_size` of 1, which is what `compress/2`
+  picks for such inputs, since even a few bytes can expand into a whole block
+  that libbz2 decodes in one go, and only for the first 64 KiB of output.
+  Everything else, including every `Bz2Ex.Stream` call, runs on a dirty CPU
+  scheduler from start to finish. The NIFs do not process their input in
+  slices and yield back to a normal scheduler in between: a single call into
+  libbz2 that completes a block sorts or decodes that whole block, which can
+  take over 100 ms at block size 9, and no slice size bounds that.
+  `compress_many/2` is handled right away when all of its elements add up to
+  4 KiB or less, and so is `decompress_many/2` under the same conditions as
+  `decompress/2`, with the 64 KiB of output shared by the whole batch.
+  `compress_async/2` and `decompress_async/2` run on the native worker pool and
+  do not occupy a scheduler at all.
   """
 
   alias Bz2Ex.Native
 
-  @type compress_opts :: [block_size: 1..9, work_factor: 0..250]
-  @type decompress_opts :: [small: boolean()]
+  @type compress_opts :: [
+          block_size: 1..9,
+          work_factor: 0..250,
+          threads: pos_integer(),
+          single_stream: boolean()
+        ]
+  @type limit_opts :: [
+          max_output_size: non_neg_integer() | :infinity,
+          max_ratio: number() | :infinity
+        ]
+  @type decompress_opts :: [
+          {:small, boolean()}
+          | {:multi_stream, boolean()}
+          | {:expected_size, non_neg_integer()}
+          | {:strict_size, boolean()}
+          | {:threads, pos_integer()}
+          | limit_opts()
+        ]
+  @type range_opts :: [offset: non_neg_integer(), length: non_neg_integer()]
+  @type async_opts :: [
+          cancel: Bz2Ex.CancelHandle.t(),
+          progress: pid(),
+          progress_interval: pos_integer()
+        ]
+  @type block_info :: %{
+          bit_offset: non_neg_integer(),
+          bit_length: pos_integer(),
+          crc: non_neg_integer()
+        }
+  @type stream_info :: %{
+          offset: non_neg_integer(),
+          length: pos_integer(),
+          block_size: 1..9,
+          crc: non_neg_integer(),
+          blocks: [block_info()]
+        }
+  @type info :: %{block_size: 1..9, streams: [stream_info()]}
   @type error_reason ::
           :param_error
           | :mem_error
@@ -28,34 +105,51 @@ defmodule Bz2Ex do
           | :data_error_magic
           | :unexpected_eof
           | :outbuff_full
+          | :output_limit_exceeded
+          | :size_mismatch
           | :config_error
           | :sequence_error
+          | :cancelled
           | :unknown_error
 
   @doc """
   Compresses binary data using bzip2.
 
+  `data` may also be an iolist, whose binaries are fed to the encoder one
+  after the other instead of being flattened first. Only the parallel path
+  (`threads` greater than one) needs the input in one piece and copies it.
+
   ## Options
 
-  - `:block_size` - Integer 1-9, default `9`
+  - `:block_size` - Integer 1-9. Defaults to the smallest block size that fits
+    the whole input, or `9` for inputs larger than that. This gives the same
+    compressed data as `9` apart from the header, while libbz2 allocates and
+    clears far less memory on both ends, which matters most for small inputs.
   - `:work_factor` - Integer 0-250, default `0`
+  - `:threads` - Positive integer, default `1`. When greater than one, the input
+    is cut into chunks that each fill exactly one block, which are compressed on
+    up to this many threads of a shared worker pool, each into a stream of its
+    own. The streams are concatenated the way pbzip2 does it, so the output can
+    be read by any decoder that supports multi-stream files, including
+    `decompress/2`.
+  - `:single_stream` - Boolean, default `false`. With `threads` greater than one,
+    splice the compressed blocks into a single stream instead, as lbzip2 does,
+    for readers that stop after the first stream. The input is cut exactly where
+    a single thread would end each block, so the output is identical to what
+    `compress/2` produces without `threads`.
+  - `:offset` and `:length` - Non-negative integers. Compress only this range
+    of `data`, counted in bytes of the flattened iodata. Default: all of it.
   """
-  @spec compress(binary(), compress_opts()) :: {:ok, binary()} | {:error, error_reason()}
-  def compress(data, opts \\ []) when is_binary(data) do
-    block_size = Keyword.get(opts, :block_size, 9)
-    work_factor = Keyword.get(opts, :work_factor, 0)
-
-    validate_block_size!(block_size)
-    validate_work_factor!(work_factor)
-
-    case Native.compress(data, block_size, work_factor) do
+  @spec compress(iodata(), [compress_opts() | range_opts()]) :: {:ok, binary()} | {:error, error_reason()}
+  def compress(data, opts \\ []) when is_binary(data) or is_list(data) do
+    case Native.compress(data, Map.merge(range_args!(opts), compress_args!(opts))) do
       {:ok, compressed} -> {:ok, compressed}
       {error_atom, _} -> {:error, error_atom}
     end
   end
 
   @doc "Compresses binary data, raising on error."
-  @spec compress!(binary(), compress_opts()) :: binary()
+  @spec compress!(iodata(), [compress_opts() | range_opts()]) :: binary()
   def compress!(data, opts \\ []) do
     case compress(data, opts) do
       {:ok, compressed} -> compressed
@@ -69,19 +163,72 @@ defmodule Bz2Ex do
   ## Options
 
   - `:small` - Boolean, default `false`
+  - `:multi_stream` - Boolean, default `true`. When `false`, only the first
+    stream is decoded and anything after it is ignored.
+  - `:max_output_size` - Non-negative integer or `:infinity`, default `:infinity`
+  - `:max_ratio` - Positive number or `:infinity`, default `:infinity`
+  - `:expected_size` - Non-negative integer. The output buffer starts out at
+    exactly this size instead of a guess based on the input, so a correct hint
+    means it never has to grow. If the hint is too small, the buffer grows as
+    it would without one.
+  - `:strict_size` - Boolean, default `false`. With `:expected_size`, fail with
+    `:size_mismatch` unless the output is exactly that size. Single-threaded
+    decoding stops as soon as the output grows past it.
+  - `:threads` - Positive integer, default `1`. When greater than one, the
+    input is split at its block boundaries, located by searching for the block
+    markers at every bit offset, and the blocks are decoded on up to this many
+    threads of a shared worker pool. This speeds up both single-stream files
+    with many blocks, as written by `bzip2`, and the concatenated streams
+    written by pbzip2 and lbzip2. Each block is checked against its stored
+    CRC, and the output is the same as with a single thread.
+  - `:offset` and `:length` - Non-negative integers. Decompress only this
+    range of `data`. The range is taken as a sub-binary, which is O(1) and
+    does not copy it. Default: all of it.
   """
-  @spec decompress(binary(), decompress_opts()) :: {:ok, binary()} | {:error, error_reason()}
+  @spec decompress(binary(), [decompress_opts() | range_opts()]) :: {:ok, binary()} | {:error, error_reason()}
   def decompress(data, opts \\ []) when is_binary(data) do
-    small = Keyword.get(opts, :small, false)
+    options = Map.merge(decompress_args!(opts, true), range_args!(opts))
 
-    case Native.decompress(data, small) do
-      {:ok, decompressed} -> {:ok, decompressed}
-      {error_atom, _} -> {:error, error_atom}
+    case Native.decompress(data, options, nil) do
+      {:ok, decompressed, _rest} -> {:ok, decompressed}
+      {error_atom, _, _} -> {:error, error_atom}
+    end
+  end
+
+  @doc """
+  Decompresses bzip2-compressed data and returns the input that follows it.
+
+  Useful when a bzip2 payload is embedded in a larger container: `rest` holds
+  every byte after the end of the decoded data, so the caller can keep parsing
+  the enclosing format. It is a sub-binary of `data`, not a copy.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+  - `:multi_stream` - Boolean, default `false`. When `true`, decoding continues
+    into any streams that directly follow the first one, and `rest` starts after
+    the last of them.
+  - `:max_output_size` - Non-negative integer or `:infinity`, default `:infinity`
+  - `:max_ratio` - Positive number or `:infinity`, default `:infinity`
+  - `:expected_size` - Non-negative integer, see `decompress/2`
+  - `:strict_size` - Boolean, default `false`, see `decompress/2`
+  - `:threads` - Positive integer, default `1`
+  - `:offset` and `:length` - Non-negative integers. Decompress only this
+    range of `data`. `rest` then holds what follows the stream within it.
+  """
+  @spec decompress_with_rest(binary(), [decompress_opts() | range_opts()]) ::
+          {:ok, binary(), binary()} | {:error, error_reason()}
+  def decompress_with_rest(data, opts \\ []) when is_binary(data) do
+    options = Map.merge(decompress_args!(opts, false), range_args!(opts))
+
+    case Native.decompress(data, options, nil) do
+      {:ok, decompressed, rest} -> {:ok, decompressed, rest}
+      {error_atom, _, _} -> {:error, error_atom}
     end
   end
 
   @doc "Decompresses bzip2-compressed data, raising on error."
-  @spec decompress!(binary(), decompress_opts()) :: binary()
+  @spec decompress!(binary(), [decompress_opts() | range_opts()]) :: binary()
   def decompress!(data, opts \\ []) do
     case decompress(data, opts) do
       {:ok, decompressed} -> decompressed
@@ -89,9 +236,300 @@ defmodule Bz2Ex do
     end
   end
 
+  @doc """
+  Decompresses bzip2-compressed data into a list of binaries.
+
+  `decompress/2` grows a single buffer by doubling it, so it briefly holds up
+  to three times the output while copying it over. Here the output is decoded
+  into separate binaries of `chunk_size` bytes each, apart from the last one,
+  which keeps peak memory close to the size of the output. The list can be
+  written to a file or socket as is.
+
+  ## Options
+
+  Takes the same options as `decompress/2`, plus:
+
+  - `:chunk_size` - Positive integer, default `1_048_576`
+  """
+  @spec decompress_iodata(binary(), [decompress_opts() | range_opts() | {:chunk_size, pos_integer()}]) ::
+          {:ok, [binary()]} | {:error, error_reason()}
+  def decompress_iodata(data, opts \\ []) when is_binary(data) do
+    options = Map.merge(decompress_args!(opts, true), range_args!(opts))
+    chunk_size = opts |> Keyword.get(:chunk_size, 1_048_576) |> validate_chunk_size!()
+
+    case Native.decompress(data, options, chunk_size) do
+      {:ok, chunks, _rest} -> {:ok, chunks}
+      {error_atom, _, _} -> {:error, error_atom}
+    end
+  end
+
+  @doc """
+  Compresses each binary in `inputs` and returns one result per element, in
+  the same order.
+
+  A failure only affects its own element, which comes back as
+  `{:error, reason}` while the others are still compressed. Compressing many
+  small binaries this way costs one NIF call instead of one per binary.
+
+  ## Options
+
+  - `:block_size` - Integer 1-9. Defaults to the smallest block size that fits
+    each element, chosen separately for every one of them.
+  - `:work_factor` - Integer 0-250, default `0`
+  - `:threads` - Positive integer, default `1`. When greater than one, the
+    elements are compressed on up to this many threads of the shared worker
+    pool. Each element is still compressed on a single thread, so the output
+    is the same as with `compress/2`.
+
+  ## Examples
+
+      [{:ok, _}, {:ok, _}] = Bz2Ex.compress_many(["first", "second"])
+  """
+  @spec compress_many([binary()], compress_opts()) :: [{:ok, binary()} | {:error, error_reason()}]
+  def compress_many(inputs, opts \\ []) when is_list(inputs) do
+    %{block_size: block_size, work_factor: work_factor, threads: threads} = compress_args!(opts)
+    Native.compress_many(inputs, block_size, work_factor, threads)
+  end
+
+  @doc """
+  Decompresses each binary in `inputs` and returns one result per element, in
+  the same order.
+
+  Every element is decoded on its own, and a failure, such as corrupt data or
+  an exceeded limit, only affects its own element, which comes back as
+  `{:error, reason}`.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+  - `:multi_stream` - Boolean, default `true`
+  - `:max_output_size` - Non-negative integer or `:infinity`, default
+    `:infinity`. Applies to each element separately.
+  - `:max_ratio` - Positive number or `:infinity`, default `:infinity`. Applies
+    to each element separately.
+  - `:threads` - Positive integer, default `1`. When greater than one, the
+    elements are decoded on up to this many threads of the shared worker
+    pool, each on a single thread.
+  """
+  @spec decompress_many([binary()], [
+          {:small, boolean()} | {:multi_stream, boolean()} | {:threads, pos_integer()} | limit_opts()
+        ]) :: [{:ok, binary()} | {:error, error_reason()}]
+  def decompress_many(inputs, opts \\ []) when is_list(inputs) do
+    Native.decompress_many(inputs, decompress_args!(opts, true))
+  end
+
+  @doc """
+  Starts compressing `data` on the native worker pool and returns right away.
+
+  Takes the same options as `compress/2`. The calling process is sent
+  `{ref, {:ok, compressed}}` or `{ref, {:error, reason}}` once the work is
+  done, where `ref` is the returned reference. Unlike `compress/2`, this does
+  not occupy a scheduler while it runs. Use `await/2` to wait for the
+  result, or match on the message in a `receive`.
+
+  ## Options
+
+  - `:cancel` - A `Bz2Ex.CancelHandle`. Cancelling it makes the job stop at
+    the next block and reply `{ref, {:error, :cancelled}}`.
+  - `:progress` - A pid that is sent `{:bz2_progress, ref, bytes_in, bytes_out}`
+    while the job runs. The counts are the totals read and written so far,
+    taken from libbz2's stream counters.
+  - `:progress_interval` - Positive integer, default `1_048_576`. A progress
+    message is sent each time `bytes_in` passes another multiple of this.
+    Messages are only sent between steps of about 1 MiB, so smaller intervals
+    do not make them more frequent.
+
+  ## Examples
+
+      ref = Bz2Ex.compress_async("Hello, World!")
+      {:ok, compressed} = Bz2Ex.await(ref)
+  """
+  @spec compress_async(binary(), [compress_opts() | range_opts() | async_opts()]) :: reference()
+  def compress_async(data, opts \\ []) when is_binary(data) do
+    {cancel, progress} = async_args!(opts)
+    Native.compress_async(data, Map.merge(range_args!(opts), compress_args!(opts)), cancel, progress)
+  end
+
+  @doc """
+  Starts decompressing `data` on the native worker pool and returns right away.
+
+  Takes the same options as `decompress/2`, plus `:cancel`, `:progress` and
+  `:progress_interval` as described for `compress_async/2`, and replies the
+  same way.
+  """
+  @spec decompress_async(binary(), [decompress_opts() | range_opts() | async_opts()]) :: reference()
+  def decompress_async(data, opts \\ []) when is_binary(data) do
+    options = Map.merge(decompress_args!(opts, true), range_args!(opts))
+    {cancel, progress} = async_args!(opts)
+    Native.decompress_async(data, options, cancel, progress)
+  end
+
+  @doc """
+  Waits for the reply to `compress_async/2` or `decompress_async/2`.
+
+  Returns `{:ok, binary}` or `{:error, reason}`. Like `Task.await/2`, exits if
+  no reply arrives within `timeout` milliseconds.
+  """
+  @spec await(reference(), timeout()) :: {:ok, binary()} | {:error, error_reason()}
+  def await(ref, timeout \\ 5000) when is_reference(ref) do
+    receive do
+      {^ref, result} -> result
+    after
+      timeout -> exit({:timeout, {__MODULE__, :await, [ref, timeout]}})
+    end
+  end
+
+  @doc """
+  Checks the integrity of bzip2-compressed data, like `bzip2 -t`.
+
+  Every stream is decoded and each block CRC and stream CRC is verified, but
+  the decompressed data is discarded instead of being collected into a binary.
+  Anything after the last stream that is not a bzip2 header is ignored, as with
+  `decompress/2`.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+
+  ## Examples
+
+      {:ok, %{uncompressed_size: 13, streams: 1, blocks: 1}} =
+        Bz2Ex.test(Bz2Ex.compress!("Hello, World!"))
+  """
+  @spec test(binary(), small: boolean()) ::
+          {:ok, %{uncompressed_size: non_neg_integer(), streams: pos_integer(), blocks: non_neg_integer()}}
+          | {:error, error_reason()}
+  def test(data, opts \\ []) when is_binary(data) do
+    small = Keyword.get(opts, :small, false)
+    Native.test(data, small)
+  end
+
+  @doc """
+  Describes the stream and block layout of bzip2 data without decompressing it.
+
+  Returns the block size digit from the first `BZh1`..`BZh9` header and, for
+  every stream, its byte offset and length, its own block size digit, its
+  combined CRC and its blocks. Each block is described by the bit offset of its
+  header from the start of `data`, its compressed length in bits and its stored
+  CRC.
+
+  bzip2 does not record where blocks end, so they are located by searching for
+  the block and end-of-stream markers, as `bzip2recover` does. Block CRCs are
+  reported as stored and not verified; use `test/2` for that.
+
+  ## Examples
+
+      {:ok, %{block_size: 1, streams: [%{blocks: [%{bit_offset: 32}]}]}} =
+        Bz2Ex.info(Bz2Ex.compress!("Hello, World!"))
+  """
+  @spec info(binary()) :: {:ok, info()} | {:error, error_reason()}
+  def info(data) when is_binary(data) do
+    Native.info(data)
+  end
+
+  # Without a block size, the NIF picks the smallest one that fits the input,
+  # whose size it knows without walking an iolist once more.
+  defp compress_args!(opts) do
+    block_size = Keyword.get(opts, :block_size)
+    work_factor = Keyword.get(opts, :work_factor, 0)
+    threads = Keyword.get(opts, :threads, 1)
+    single_stream = Keyword.get(opts, :single_stream, false)
+
+    if block_size, do: validate_block_size!(block_size)
+    validate_work_factor!(work_factor)
+    validate_threads!(threads)
+
+    %{block_size: block_size, work_factor: work_factor, threads: threads, single_stream: single_stream}
+  end
+
+  defp decompress_args!(opts, multi_stream_default) do
+    small = Keyword.get(opts, :small, false)
+    multi_stream = Keyword.get(opts, :multi_stream, multi_stream_default)
+    max_output_size = opts |> Keyword.get(:max_output_size, :infinity) |> validate_max_output_size!()
+    max_ratio = opts |> Keyword.get(:max_ratio, :infinity) |> validate_max_ratio!()
+    expected_size = opts |> Keyword.get(:expected_size) |> validate_expected_size!()
+    strict_size = Keyword.get(opts, :strict_size, false)
+    threads = Keyword.get(opts, :threads, 1)
+
+    validate_threads!(threads)
+
+    %{
+      small: small,
+      multi_stream: multi_stream,
+      max_output_size: max_output_size,
+      max_ratio: max_ratio,
+      expected_size: expected_size,
+      strict_size: strict_size,
+      threads: threads
+    }
+  end
+
+  # The range is checked against the input in the NIF.
+  defp range_args!(opts) do
+    offset = opts |> Keyword.get(:offset, 0) |> validate_offset!()
+    length = opts |> Keyword.get(:length) |> validate_length!()
+    %{offset: offset, length: length}
+  end
+
+  defp async_args!(opts) do
+    cancel = Keyword.get(opts, :cancel)
+
+    progress =
+      case Keyword.get(opts, :progress) do
+        nil ->
+          nil
+
+        pid when is_pid(pid) ->
+          {pid, opts |> Keyword.get(:progress_interval, 1_048_576) |> validate_progress_interval!()}
+
+        other ->
+          raise ArgumentError, "progress must be a pid, got: #{inspect(other)}"
+      end
+
+    {cancel, progress}
+  end
+
   defp validate_block_size!(bs) when bs in 1..9, do: :ok
   defp validate_block_size!(bs), do: raise(ArgumentError, "block_size must be 1-9, got: #{inspect(bs)}")
 
   defp validate_work_factor!(wf) when wf in 0..250, do: :ok
   defp validate_work_factor!(wf), do: raise(ArgumentError, "work_factor must be 0-250, got: #{inspect(wf)}")
+
+  defp validate_threads!(n) when is_integer(n) and n > 0, do: :ok
+  defp validate_threads!(n), do: raise(ArgumentError, "threads must be a positive integer, got: #{inspect(n)}")
+
+  defp validate_progress_interval!(n) when is_integer(n) and n > 0, do: n
+
+  defp validate_progress_interval!(n),
+    do: raise(ArgumentError, "progress_interval must be a positive integer, got: #{inspect(n)}")
+
+  defp validate_offset!(n) when is_integer(n) and n >= 0, do: n
+  defp validate_offset!(n), do: raise(ArgumentError, "offset must be a non-negative integer, got: #{inspect(n)}")
+
+  defp validate_length!(nil), do: nil
+  defp validate_length!(n) when is_integer(n) and n >= 0, do: n
+  defp validate_length!(n), do: raise(ArgumentError, "length must be a non-negative integer, got: #{inspect(n)}")
+
+  defp validate_chunk_size!(n) when is_integer(n) and n > 0, do: n
+
+  defp validate_chunk_size!(n),
+    do: raise(ArgumentError, "chunk_size must be a positive integer, got: #{inspect(n)}")
+
+  defp validate_expected_size!(nil), do: nil
+  defp validate_expected_size!(n) when is_integer(n) and n >= 0, do: n
+
+  defp validate_expected_size!(n),
+    do: raise(ArgumentError, "expected_size must be a non-negative integer, got: #{inspect(n)}")
+
+  defp validate_max_output_size!(:infinity), do: nil
+  defp validate_max_output_size!(n) when is_integer(n) and n >= 0, do: n
+
+  defp validate_max_output_size!(n),
+    do: raise(ArgumentError, "max_output_size must be a non-negative integer or :infinity, got: #{inspect(n)}")
+
+  defp validate_max_ratio!(:infinity), do: nil
+  defp validate_max_ratio!(r) when is_number(r) and r > 0, do: r / 1
+
+  defp validate_max_ratio!(r),
+    do: raise(ArgumentError, "max_ratio must be a positive number or :infinity, got: #{inspect(r)}")
 end
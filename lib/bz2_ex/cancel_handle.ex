@@ -0,0 +1,40 @@
+defmodule Bz2Ex.CancelHandle do
+  @moduledoc """
+  Stops async and streaming operations early.
+
+  Pass a handle as the `:cancel` option to `Bz2Ex.compress_async/2`,
+  `Bz2Ex.decompress_async/2`, `Bz2Ex.Stream.compress_init/1` or
+  `Bz2Ex.Stream.decompress_init/1`. The native code checks it between blocks
+  and between output windows of about 1 MiB. Once `cancel/1` has been called,
+  the operation frees its buffers and reports `{:error, :cancelled}`: async
+  jobs reply with it, and streams return it from their next call.
+
+  One handle can be shared by any number of operations and can be cancelled
+  from any process.
+
+  ## Examples
+
+      handle = Bz2Ex.CancelHandle.new()
+      ref = Bz2Ex.compress_async(data, cancel: handle)
+      :ok = Bz2Ex.CancelHandle.cancel(handle)
+      {:error, :cancelled} = Bz2Ex.await(ref)
+  """
+
+  alias Bz2Ex.Native
+
+  @opaque t :: reference()
+
+  @doc "Creates a handle that has not been cancelled."
+  @spec new() :: t()
+  def new, do: Native.cancel_handle_new()
+
+  @doc """
+  Cancels every operation using `handle`, including ones started later.
+  """
+  @spec cancel(t()) :: :ok
+  def cancel(handle), do: Native.cancel_handle_cancel(handle)
+
+  @doc "Returns whether `cancel/1` has been called on `handle`."
+  @spec cancelled?(t()) :: boolean()
+  def cancelled?(handle), do: Native.cancel_handle_cancelled(handle)
+end
@@ -16,8 +16,11 @@ defmodule Bz2Ex.Error do
   defp format_reason(:data_error_magic), do: "invalid bzip2 header"
   defp format_reason(:unexpected_eof), do: "unexpected end of data"
   defp format_reason(:outbuff_full), do: "output buffer full"
+  defp format_reason(:output_limit_exceeded), do: "output size limit exceeded"
+  defp format_reason(:size_mismatch), do: "output size does not match the expected size"
   defp format_reason(:config_error), do: "configuration error"
   defp format_reason(:sequence_error), do: "invalid operation sequence"
   defp format_reason(:io_error), do: "I/O error"
+  defp format_reason(:cancelled), do: "cancelled"
   defp format_reason(reason), do: inspect(reason)
 end
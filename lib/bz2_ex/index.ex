@@ -0,0 +1,110 @@
+defmodule Bz2Ex.Index do
+  @moduledoc """
+  Random access into bzip2 data through a block index.
+
+  bzip2 compresses data in independent blocks of up to 900k. An index records
+  where each block starts and ends in the compressed data and where its output
+  lands in the decompressed data, so a read only has to decode the blocks that
+  cover the requested range instead of everything in front of it.
+
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      {:ok, chunk} = Bz2Ex.Index.read(index, compressed, 10_000_000, 1024)
+
+  Building an index decodes the whole input once. The index only holds
+  offsets, so the compressed data has to be passed to every `read/5` call.
+
+  ## Persisting an index
+
+  `encode/1` turns an index into a small binary that can be stored next to the
+  archive, and `decode/1` loads it back without rescanning the archive:
+
+      File.write!("dump.bz2.idx", Bz2Ex.Index.encode(index))
+      {:ok, index} = Bz2Ex.Index.decode(File.read!("dump.bz2.idx"))
+
+  The format is versioned and ends in a CRC-32 of its contents, so truncated or
+  corrupted index files are rejected rather than misread.
+  """
+
+  alias Bz2Ex.Native
+
+  @opaque t :: reference()
+  @type block :: %{
+          bit_offset: non_neg_integer(),
+          bit_length: pos_integer(),
+          uncompressed_offset: non_neg_integer(),
+          uncompressed_size: pos_integer(),
+          crc: non_neg_integer()
+        }
+  @type decode_error :: :invalid_index | :unsupported_version
+  @type info :: %{
+          block_size: 1..9,
+          uncompressed_size: non_neg_integer(),
+          blocks: [block()]
+        }
+
+  @doc """
+  Builds a block index for bzip2 data.
+
+  Concatenated streams are indexed as one continuous run of blocks.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+  """
+  @spec build(binary(), small: boolean()) :: {:ok, t()} | {:error, Bz2Ex.error_reason()}
+  def build(data, opts \\ []) when is_binary(data) do
+    small = Keyword.get(opts, :small, false)
+    Native.index_build(data, small)
+  end
+
+  @doc """
+  Decompresses `length` bytes starting at uncompressed byte `offset`.
+
+  `data` must be the same binary the index was built from. Like `pread`, a
+  range that extends past the end of the data is cut short, and one that starts
+  past the end returns an empty binary.
+
+  Returns `{:error, :invalid_index}` if a block the range needs lies past the
+  end of `data`.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+  """
+  @spec read(t(), binary(), non_neg_integer(), non_neg_integer(), small: boolean()) ::
+          {:ok, binary()} | {:error, Bz2Ex.error_reason() | :invalid_index}
+  def read(index, data, offset, length, opts \\ [])
+      when is_binary(data) and is_integer(offset) and offset >= 0 and is_integer(length) and
+             length >= 0 do
+    small = Keyword.get(opts, :small, false)
+
+    case Native.index_read(index, data, offset, length, small) do
+      {:ok, chunk} -> {:ok, chunk}
+      {error_atom, _} -> {:error, error_atom}
+    end
+  end
+
+  @doc "Returns the block size digit, total uncompressed size and block list."
+  @spec info(t()) :: info()
+  def info(index) do
+    Native.index_info(index)
+  end
+
+  @doc "Serializes an index into the versioned, checksummed sidecar format."
+  @spec encode(t()) :: binary()
+  def encode(index) do
+    Native.index_encode(index)
+  end
+
+  @doc """
+  Loads an index written by `encode/1`.
+
+  Returns `{:error, :invalid_index}` if the data is truncated, corrupted or not
+  an index at all, and `{:error, :unsupported_version}` if it was written by a
+  newer format version.
+  """
+  @spec decode(binary()) :: {:ok, t()} | {:error, decode_error()}
+  def decode(data) when is_binary(data) do
+    Native.index_decode(data)
+  end
+end
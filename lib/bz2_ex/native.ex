@@ -18,13 +18,36 @@ defmodule Bz2Ex.Native do
       x86_64-unknown-linux-musl
       x86_64-unknown-freebsd
     ),
-    version: @version
+    version: @version,
+    load_data_fun: {Bz2Ex.Pool, :load_data}
 
-  def compress(_input, _block_size, _work_factor), do: :erlang.nif_error(:nif_not_loaded)
-  def decompress(_input, _small), do: :erlang.nif_error(:nif_not_loaded)
-  def compress_stream_init(_block_size, _work_factor), do: :erlang.nif_error(:nif_not_loaded)
+  def compress(_input, _options), do: :erlang.nif_error(:nif_not_loaded)
+  def decompress(_input, _options, _chunk_size), do: :erlang.nif_error(:nif_not_loaded)
+  def compress_async(_input, _options, _cancel, _progress), do: :erlang.nif_error(:nif_not_loaded)
+  def decompress_async(_input, _options, _cancel, _progress), do: :erlang.nif_error(:nif_not_loaded)
+
+  def compress_many(_inputs, _block_size, _work_factor, _threads), do: :erlang.nif_error(:nif_not_loaded)
+
+  def decompress_many(_inputs, _options), do: :erlang.nif_error(:nif_not_loaded)
+
+  def test(_input, _small), do: :erlang.nif_error(:nif_not_loaded)
+  def info(_input), do: :erlang.nif_error(:nif_not_loaded)
+  def index_build(_input, _small), do: :erlang.nif_error(:nif_not_loaded)
+  def index_read(_index, _input, _offset, _length, _small), do: :erlang.nif_error(:nif_not_loaded)
+  def index_info(_index), do: :erlang.nif_error(:nif_not_loaded)
+  def index_encode(_index), do: :erlang.nif_error(:nif_not_loaded)
+  def index_decode(_input), do: :erlang.nif_error(:nif_not_loaded)
+  def compress_stream_init(_block_size, _work_factor, _cancel), do: :erlang.nif_error(:nif_not_loaded)
+  def parallel_compress_stream_init(_block_size, _work_factor, _threads, _cancel),
+    do: :erlang.nif_error(:nif_not_loaded)
   def compress_stream_deflate(_stream, _input), do: :erlang.nif_error(:nif_not_loaded)
   def compress_stream_finish(_stream), do: :erlang.nif_error(:nif_not_loaded)
-  def decompress_stream_init(_small), do: :erlang.nif_error(:nif_not_loaded)
+  def decompress_stream_init(_small, _max_output_size, _max_ratio, _cancel), do: :erlang.nif_error(:nif_not_loaded)
   def decompress_stream_inflate(_stream, _input), do: :erlang.nif_error(:nif_not_loaded)
+  def cancel_handle_new(), do: :erlang.nif_error(:nif_not_loaded)
+  def cancel_handle_cancel(_handle), do: :erlang.nif_error(:nif_not_loaded)
+  def cancel_handle_cancelled(_handle), do: :erlang.nif_error(:nif_not_loaded)
+  def pool_size(), do: :erlang.nif_error(:nif_not_loaded)
+  def pool_set_size(_size), do: :erlang.nif_error(:nif_not_loaded)
+  def pool_stats(), do: :erlang.nif_error(:nif_not_loaded)
 end
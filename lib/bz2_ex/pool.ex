@@ -0,0 +1,62 @@
+defmodule Bz2Ex.Pool do
+  @moduledoc """
+  The native worker thread pool used by parallel operations.
+
+  Options such as `threads:` on `Bz2Ex.compress/2`, `Bz2Ex.decompress/2` and
+  `Bz2Ex.Stream.compress_init/1` run their work on a single pool of native
+  threads shared by the whole node. By default it has one thread per CPU.
+  Those threads compete with the BEAM's own schedulers, so on a busy node it
+  can pay to make the pool smaller:
+
+      config :bz2_ex, pool_size: 8
+
+  The configured size is applied when the NIF library is loaded, and it can
+  be changed at runtime with `set_size/1`. Threads are only started once a
+  parallel operation needs them.
+  """
+
+  alias Bz2Ex.Native
+
+  @type stats :: %{
+          size: pos_integer(),
+          workers: non_neg_integer(),
+          active: non_neg_integer(),
+          queued: non_neg_integer()
+        }
+
+  @doc "Returns the number of worker threads the pool runs."
+  @spec size() :: pos_integer()
+  def size, do: Native.pool_size()
+
+  @doc """
+  Changes the number of worker threads.
+
+  Growing the pool starts new threads as soon as there is work for them.
+  When shrinking, surplus threads exit after finishing their current job.
+  """
+  @spec set_size(pos_integer()) :: :ok
+  def set_size(size) when is_integer(size) and size > 0, do: Native.pool_set_size(size)
+
+  def set_size(size),
+    do: raise(ArgumentError, "size must be a positive integer, got: #{inspect(size)}")
+
+  @doc """
+  Returns a snapshot of the pool.
+
+  - `:size` - configured number of threads
+  - `:workers` - threads currently running, which lags behind `:size` while
+    the pool grows or shrinks
+  - `:active` - threads currently running a job
+  - `:queued` - jobs waiting for a free thread
+  """
+  @spec stats() :: stats()
+  def stats, do: Native.pool_stats()
+
+  @doc false
+  def load_data do
+    case Application.get_env(:bz2_ex, :pool_size) do
+      size when is_integer(size) and size > 0 -> size
+      _ -> 0
+    end
+  end
+end
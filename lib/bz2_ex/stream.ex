@@ -20,26 +20,56 @@ defmodule Bz2Ex.Stream do
 
   @opaque compress_stream :: reference()
   @opaque decompress_stream :: reference()
-  @type compress_opts :: [block_size: 1..9, work_factor: 0..250]
-  @type decompress_opts :: [small: boolean()]
+  @type compress_opts :: [
+          block_size: 1..9,
+          work_factor: 0..250,
+          threads: pos_integer(),
+          cancel: Bz2Ex.CancelHandle.t()
+        ]
+  @type decompress_opts :: [{:small, boolean()} | {:cancel, Bz2Ex.CancelHandle.t()} | Bz2Ex.limit_opts()]
   @type decompress_status :: :ready | :finished
 
-  @doc "Initialize a compression stream."
+  @doc """
+  Initialize a compression stream.
+
+  ## Options
+
+  - `:block_size` - Integer 1-9, default `9`
+  - `:work_factor` - Integer 0-250, default `0`
+  - `:threads` - Positive integer, default `1`. When greater than one, input is
+    buffered until it fills a block, and full blocks are compressed on up to
+    this many threads of a shared worker pool while `compress/2` returns. Their
+    output is handed back in order by later `compress/2` calls and by
+    `compress_finish/1`, which waits for the remaining blocks. The complete
+    output is identical to that of a single-threaded stream.
+  - `:cancel` - A `Bz2Ex.CancelHandle`. Once it is cancelled, the next
+    `compress/2` or `compress_finish/1` call stops at a block boundary, closes
+    the stream and returns `{:error, :cancelled}`.
+  """
   @spec compress_init(compress_opts()) :: {:ok, compress_stream()} | {:error, Bz2Ex.error_reason()}
   def compress_init(opts \\ []) do
     block_size = Keyword.get(opts, :block_size, 9)
     work_factor = Keyword.get(opts, :work_factor, 0)
+    threads = Keyword.get(opts, :threads, 1)
+    cancel = Keyword.get(opts, :cancel)
     validate_block_size!(block_size)
     validate_work_factor!(work_factor)
-    Native.compress_stream_init(block_size, work_factor)
+    validate_threads!(threads)
+
+    if threads > 1 do
+      Native.parallel_compress_stream_init(block_size, work_factor, threads, cancel)
+    else
+      Native.compress_stream_init(block_size, work_factor, cancel)
+    end
   end
 
-  @doc "Feed data into a compression stream."
-  @spec compress(compress_stream(), binary()) ::
+  @doc "Feed data into a compression stream. `data` may be an iolist."
+  @spec compress(compress_stream(), iodata()) ::
           {:ok, binary(), compress_stream()} | {:error, Bz2Ex.error_reason()}
-  def compress(stream, data) when is_binary(data) do
+  def compress(stream, data) when is_binary(data) or is_list(data) do
     case Native.compress_stream_deflate(stream, data) do
       {:ok, chunk} -> {:ok, chunk, stream}
+      {:error, reason} when is_atom(reason) -> {:error, reason}
       {error_atom, _} -> {:error, error_atom}
     end
   end
@@ -49,16 +79,36 @@ defmodule Bz2Ex.Stream do
   def compress_finish(stream) do
     case Native.compress_stream_finish(stream) do
       {:ok, chunk} -> {:ok, chunk}
+      {:error, reason} when is_atom(reason) -> {:error, reason}
       {error_atom, _} -> {:error, error_atom}
     end
   end
 
-  @doc "Initialize a decompression stream."
+  @doc """
+  Initialize a decompression stream.
+
+  ## Options
+
+  - `:small` - Boolean, default `false`
+  - `:max_output_size` - Non-negative integer or `:infinity`, default `:infinity`.
+    Caps the total output across all `decompress/2` calls.
+  - `:max_ratio` - Positive number or `:infinity`, default `:infinity`. Caps the
+    total output relative to the compressed input consumed so far.
+  - `:cancel` - A `Bz2Ex.CancelHandle`. Once it is cancelled, `decompress/2`
+    stops before the next output window of about 1 MiB, closes the stream and
+    returns `{:error, :cancelled}`.
+
+  Exceeding either limit makes `decompress/2` return
+  `{:error, :output_limit_exceeded}` and closes the stream.
+  """
   @spec decompress_init(decompress_opts()) ::
           {:ok, decompress_stream()} | {:error, Bz2Ex.error_reason()}
   def decompress_init(opts \\ []) do
     small = Keyword.get(opts, :small, false)
-    Native.decompress_stream_init(small)
+    max_output_size = opts |> Keyword.get(:max_output_size, :infinity) |> validate_max_output_size!()
+    max_ratio = opts |> Keyword.get(:max_ratio, :infinity) |> validate_max_ratio!()
+    cancel = Keyword.get(opts, :cancel)
+    Native.decompress_stream_init(small, max_output_size, max_ratio, cancel)
   end
 
   @doc "Feed compressed data into a decompression stream."
@@ -67,10 +117,36 @@ defmodule Bz2Ex.Stream do
           | {:error, Bz2Ex.error_reason()}
   def decompress(stream, data) when is_binary(data) do
     case Native.decompress_stream_inflate(stream, data) do
-      {:ok, chunk, status} when status in [:ready, :finished] ->
+      {:ok, chunk, status, _rest} when status in [:ready, :finished] ->
         {:ok, chunk, status, stream}
 
-      {error_atom, _, _} ->
+      {:error, reason} ->
+        {:error, reason}
+
+      {error_atom, _, _, _} ->
+        {:error, error_atom}
+    end
+  end
+
+  @doc """
+  Feed compressed data into a decompression stream, keeping any input that
+  follows the end of the bzip2 stream.
+
+  `rest` is empty while the stream is `:ready`. Once it is `:finished`, `rest`
+  holds the unconsumed tail of `data` so the enclosing format can be parsed.
+  """
+  @spec decompress_with_rest(decompress_stream(), binary()) ::
+          {:ok, binary(), decompress_status(), binary(), decompress_stream()}
+          | {:error, Bz2Ex.error_reason()}
+  def decompress_with_rest(stream, data) when is_binary(data) do
+    case Native.decompress_stream_inflate(stream, data) do
+      {:ok, chunk, status, rest} when status in [:ready, :finished] ->
+        {:ok, chunk, status, rest, stream}
+
+      {:error, reason} ->
+        {:error, reason}
+
+      {error_atom, _, _, _} ->
         {:error, error_atom}
     end
   end
@@ -80,4 +156,19 @@ defmodule Bz2Ex.Stream do
 
   defp validate_work_factor!(wf) when wf in 0..250, do: :ok
   defp validate_work_factor!(wf), do: raise(ArgumentError, "work_factor must be 0-250, got: #{inspect(wf)}")
+
+  defp validate_threads!(n) when is_integer(n) and n > 0, do: :ok
+  defp validate_threads!(n), do: raise(ArgumentError, "threads must be a positive integer, got: #{inspect(n)}")
+
+  defp validate_max_output_size!(:infinity), do: nil
+  defp validate_max_output_size!(n) when is_integer(n) and n >= 0, do: n
+
+  defp validate_max_output_size!(n),
+    do: raise(ArgumentError, "max_output_size must be a non-negative integer or :infinity, got: #{inspect(n)}")
+
+  defp validate_max_ratio!(:infinity), do: nil
+  defp validate_max_ratio!(r) when is_number(r) and r > 0, do: r / 1
+
+  defp validate_max_ratio!(r),
+    do: raise(ArgumentError, "max_ratio must be a positive number or :infinity, got: #{inspect(r)}")
 end
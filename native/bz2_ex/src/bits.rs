@@ -0,0 +1,140 @@
+//! Bit-level access to bzip2 data.
+//!
+//! bzip2 packs everything MSB-first with no byte alignment between blocks, so
+//! finding and splicing blocks means working in bit offsets rather than bytes.
+
+/// Reads `n` (at most 57) bits starting at bit offset `pos`. Bits past the end
+/// of `data` read as zero.
+pub fn read_bits(data: &[u8], pos: u64, n: u32) -> u64 {
+    debug_assert!(n <= 57);
+    let byte = (pos / 8) as usize;
+    let mut word = [0u8; 8];
+    if byte < data.len() {
+        let available = (data.len() - byte).min(8);
+        word[..available].copy_from_slice(&data[byte..byte + available]);
+    }
+    let word = u64::from_be_bytes(word);
+    if n == 0 {
+        return 0;
+    }
+    (word << (pos % 8)) >> (64 - n)
+}
+
+/// Total number of bits in `data`.
+pub fn bit_len(data: &[u8]) -> u64 {
+    data.len() as u64 * 8
+}
+
+/// Finds the first bit offset at or after `start` where the next 48 bits equal
+/// one of `patterns`, returning the offset and the matching pattern.
+pub fn find_pattern48(data: &[u8], start: u64, patterns: &[u64]) -> Option<(u64, u64)> {
+    const MASK: u64 = (1 << 48) - 1;
+
+    if start + 48 > bit_len(data) {
+        return None;
+    }
+
+    // `reg` always holds the bits of every byte up to and including `i`. Each
+    // new byte completes eight candidate windows, one per shift, which are
+    // checked from the earliest start to the latest.
+    let first = (start / 8) as usize;
+    let mut reg: u64 = 0;
+    let mut loaded: u64 = 0;
+    for (i, &byte) in data.iter().enumerate().skip(first) {
+        reg = (reg << 8) | u64::from(byte);
+        loaded += 8;
+        if loaded < 48 {
+            continue;
+        }
+        let end = (i as u64 + 1) * 8;
+        for shift in (0..8).rev() {
+            if loaded < 48 + shift {
+                continue;
+            }
+            let pos = end - shift - 48;
+            if pos < start {
+                continue;
+            }
+            let window = (reg >> shift) & MASK;
+            if let Some(&pattern) = patterns.iter().find(|&&p| p == window) {
+                return Some((pos, pattern));
+            }
+        }
+    }
+
+    None
+}
+
+/// Appends bits MSB-first to a byte buffer.
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet flushed to `bytes`, right-aligned.
+    acc: u64,
+    acc_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            acc: 0,
+            acc_bits: 0,
+        }
+    }
+
+    /// Writes the low `n` (at most 32) bits of `value`.
+    pub fn write(&mut self, value: u64, n: u32) {
+        debug_assert!(n <= 32);
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.acc_bits += n;
+        while self.acc_bits >= 8 {
+            self.acc_bits -= 8;
+            self.bytes.push((self.acc >> self.acc_bits) as u8);
+        }
+        self.acc &= (1 << self.acc_bits) - 1;
+    }
+
+    /// Copies bits `start..end` of `data`.
+    pub fn copy_bits(&mut self, data: &[u8], start: u64, end: u64) {
+        let mut pos = start;
+
+        let head = ((8 - pos % 8) % 8).min(end - pos) as u32;
+        self.write(read_bits(data, pos, head), head);
+        pos += u64::from(head);
+
+        let whole = ((end - pos) / 8) as usize;
+        let first = (pos / 8) as usize;
+        let source = &data[first..first + whole];
+        if self.acc_bits == 0 {
+            self.bytes.extend_from_slice(source);
+        } else {
+            let shift = self.acc_bits;
+            self.bytes.reserve(whole);
+            for &byte in source {
+                self.acc = (self.acc << 8) | u64::from(byte);
+                self.bytes.push((self.acc >> shift) as u8);
+                self.acc &= (1 << shift) - 1;
+            }
+        }
+        pos += whole as u64 * 8;
+
+        let tail = (end - pos) as u32;
+        self.write(read_bits(data, pos, tail), tail);
+    }
+
+    /// Takes every complete byte written so far, leaving any partial byte.
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    /// Pads the last byte with zero bits and returns the buffer.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.acc_bits > 0 {
+            self.bytes.push((self.acc << (8 - self.acc_bits)) as u8);
+        }
+        self.bytes
+    }
+}
@@ -0,0 +1,198 @@
+//! Pieces shared by the [`Encoder`](crate::encoder::Encoder) and
+//! [`Decoder`](crate::decoder::Decoder) wrappers around a raw `bz_stream`.
+
+use libbz2_rs_sys::bz_stream;
+
+/// Largest slice handed to libbz2 in one call. `avail_in` and `avail_out` are
+/// `u32`, so anything bigger has to be fed through in several windows.
+#[cfg(not(test))]
+pub const WINDOW: usize = u32::MAX as usize;
+
+/// Small enough for tests to cross many windows without 4 GiB of data.
+#[cfg(test)]
+pub const WINDOW: usize = 1000;
+
+pub fn new_stream() -> Box<bz_stream> {
+    Box::new(bz_stream {
+        next_in: std::ptr::null_mut(),
+        avail_in: 0,
+        total_in_lo32: 0,
+        total_in_hi32: 0,
+        next_out: std::ptr::null_mut(),
+        avail_out: 0,
+        total_out_lo32: 0,
+        total_out_hi32: 0,
+        state: std::ptr::null_mut(),
+        bzalloc: None,
+        bzfree: None,
+        opaque: std::ptr::null_mut(),
+    })
+}
+
+/// Points the stream at the first [`WINDOW`] bytes of `input` and `output`.
+pub fn set_windows(stream: &mut bz_stream, input: &[u8], output: &mut [u8]) {
+    stream.next_in = input.as_ptr().cast();
+    stream.avail_in = input.len().min(WINDOW) as u32;
+    stream.next_out = output.as_mut_ptr().cast();
+    stream.avail_out = output.len().min(WINDOW) as u32;
+}
+
+/// Full 64-bit `(total_in, total_out)` counters of a stream.
+pub fn totals(stream: &bz_stream) -> (u64, u64) {
+    (
+        (u64::from(stream.total_in_hi32) << 32) | u64::from(stream.total_in_lo32),
+        (u64::from(stream.total_out_hi32) << 32) | u64::from(stream.total_out_lo32),
+    )
+}
+
+/// Outcome of a single `BZ2_bzCompress` or `BZ2_bzDecompress` call.
+pub struct Step {
+    pub code: i32,
+    pub consumed: usize,
+    pub produced: usize,
+    /// The output window was filled, so libbz2 may have more to write.
+    pub output_full: bool,
+}
+
+impl Step {
+    pub fn new(code: i32, stream: &bz_stream, input: &[u8], output: &[u8]) -> Self {
+        Self {
+            code,
+            consumed: input.len().min(WINDOW) - stream.avail_in as usize,
+            produced: output.len().min(WINDOW) - stream.avail_out as usize,
+            output_full: stream.avail_out == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WINDOW;
+    use crate::control::Control;
+    use crate::decoder::{self, Decoder, Expected, Limits};
+    use crate::encoder::{self, Encoder};
+    use libbz2_rs_sys::{BZ2_bzBuffToBuffCompress, BZ_OK, BZ_STREAM_END};
+
+    const NO_LIMITS: Limits = Limits {
+        max_output_size: None,
+        max_ratio: None,
+    };
+
+    /// Text with some noise mixed in, so that it neither compresses to
+    /// nothing nor fails to compress at all.
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                if i % 4 == 0 {
+                    (state >> 24) as u8
+                } else {
+                    b"windowed bzip2 "[i % 15]
+                }
+            })
+            .collect()
+    }
+
+    /// What libbz2 makes of `input` in a single call, without any windows.
+    fn reference(input: &[u8], block_size: i32) -> Vec<u8> {
+        let mut output = vec![0u8; encoder::max_compressed_size(input.len())];
+        let mut len = output.len() as u32;
+        let code = unsafe {
+            BZ2_bzBuffToBuffCompress(
+                output.as_mut_ptr().cast(),
+                &mut len,
+                input.as_ptr().cast_mut().cast(),
+                input.len() as u32,
+                block_size,
+                0,
+                0,
+            )
+        };
+        assert_eq!(code, BZ_OK);
+        output.truncate(len as usize);
+        output
+    }
+
+    #[test]
+    fn one_shot_round_trip_across_windows() {
+        let control = Control::default();
+        for len in [0, 1, WINDOW - 1, WINDOW, WINDOW + 1, 50 * WINDOW, 250_000] {
+            let input = sample(len);
+            let compressed = encoder::compress(&input[..], 1, 0, &control).unwrap();
+            assert_eq!(compressed, reference(&input, 1), "len {len}");
+
+            let decoded = decoder::decompress(
+                &compressed,
+                false,
+                true,
+                NO_LIMITS,
+                Expected::default(),
+                &control,
+            )
+            .unwrap();
+            assert_eq!(decoded.data, input, "len {len}");
+            assert_eq!(decoded.consumed, compressed.len());
+        }
+    }
+
+    #[test]
+    fn streaming_round_trip_across_windows() {
+        let control = Control::default();
+        let input = sample(250_000);
+        let mut encoder = Encoder::new(1, 0).unwrap();
+        let mut compressed = Vec::new();
+        for chunk in input.chunks(7 * WINDOW + 3) {
+            encoder::run(&mut encoder, chunk, &mut compressed, &control).unwrap();
+        }
+        encoder::finish(&mut encoder, &mut compressed, &control).unwrap();
+        assert_eq!(compressed, reference(&input, 1));
+        assert_eq!(
+            encoder.totals(),
+            (input.len() as u64, compressed.len() as u64)
+        );
+    }
+
+    #[test]
+    fn decoder_steps_stay_within_a_window() {
+        let input = sample(250_000);
+        let compressed = reference(&input, 1);
+        let mut decoder = Decoder::new(false).unwrap();
+        let mut output = vec![0u8; input.len() + 1];
+        let (mut pos, mut filled) = (0, 0);
+        loop {
+            let step = decoder.step(&compressed[pos..], &mut output[filled..]);
+            assert!(step.consumed <= WINDOW && step.produced <= WINDOW);
+            pos += step.consumed;
+            filled += step.produced;
+            match step.code {
+                BZ_OK => {}
+                BZ_STREAM_END => break,
+                code => panic!("unexpected code {code}"),
+            }
+        }
+        assert_eq!(&output[..filled], &input[..]);
+        assert_eq!(pos, compressed.len());
+        assert_eq!(
+            decoder.totals(),
+            (compressed.len() as u64, input.len() as u64)
+        );
+    }
+
+    #[test]
+    fn concatenated_streams_across_windows() {
+        let control = Control::default();
+        let (first, second) = (sample(30 * WINDOW), sample(45 * WINDOW + 7));
+        let compressed = [reference(&first, 1), reference(&second, 9)].concat();
+        let decoded = decoder::decompress(
+            &compressed,
+            false,
+            true,
+            NO_LIMITS,
+            Expected::default(),
+            &control,
+        )
+        .unwrap();
+        assert_eq!(decoded.data, [first, second].concat());
+    }
+}
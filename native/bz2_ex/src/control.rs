@@ -0,0 +1,101 @@
+//! Lets the caller of a long-running job stop it early and follow along.
+//!
+//! Encoding and decoding loops hand libbz2 at most [`STEP`] bytes at a time
+//! and call [`Control::check`] between steps and between blocks, giving up
+//! with [`CANCELLED`] once the job's cancellation flag has been raised. After
+//! every step they also pass the `total_in`/`total_out` counters of their
+//! `bz_stream` to a [`Counters`], which adds them to the job's [`Progress`].
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Returned in place of a libbz2 status code when a job was cancelled.
+pub const CANCELLED: i32 = -101;
+
+/// Largest amount of input or output handled between two checks.
+pub const STEP: usize = 1 << 20;
+
+#[derive(Clone, Default)]
+pub struct Control {
+    cancelled: Option<Arc<AtomicBool>>,
+    progress: Option<Arc<Progress>>,
+}
+
+impl Control {
+    pub fn new(cancelled: Option<Arc<AtomicBool>>, progress: Option<Arc<Progress>>) -> Self {
+        Self {
+            cancelled,
+            progress,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), i32> {
+        match &self.cancelled {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(CANCELLED),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Running byte totals of a job, which may be spread over several streams
+/// and threads.
+pub struct Progress {
+    interval: u64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Input total at which the next report is due.
+    next: AtomicU64,
+    report: Box<dyn Fn(u64, u64) + Send + Sync>,
+}
+
+impl Progress {
+    /// Calls `report(bytes_in, bytes_out)` every time the input total passes
+    /// another multiple of `interval`, which must not be zero.
+    pub fn new(interval: u64, report: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        Self {
+            interval,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            next: AtomicU64::new(interval),
+            report: Box::new(report),
+        }
+    }
+
+    fn add(&self, bytes_in: u64, bytes_out: u64) {
+        let total_in = self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed) + bytes_in;
+        let total_out = self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed) + bytes_out;
+        let next = self.next.load(Ordering::Relaxed);
+        if total_in < next {
+            return;
+        }
+        // Only the thread that moves the threshold on reports, so each
+        // interval is reported at most once.
+        let following = (total_in / self.interval + 1) * self.interval;
+        if self
+            .next
+            .compare_exchange(next, following, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            (self.report)(total_in, total_out);
+        }
+    }
+}
+
+/// Remembers how much of one stream's counters has been passed on already.
+#[derive(Default)]
+pub struct Counters {
+    total_in: u64,
+    total_out: u64,
+}
+
+impl Counters {
+    /// Adds whatever `(total_in, total_out)` grew by since the last update
+    /// to the progress of `control`.
+    pub fn update(&mut self, control: &Control, (total_in, total_out): (u64, u64)) {
+        if let Some(progress) = &control.progress {
+            progress.add(total_in - self.total_in, total_out - self.total_out);
+        }
+        self.total_in = total_in;
+        self.total_out = total_out;
+    }
+}
@@ -0,0 +1,394 @@
+//! Incremental decoding on top of a raw `bz_stream`.
+//!
+//! The one-shot NIFs used to go through `BZ2_bzBuffToBuffDecompress`, which
+//! stops at the first end-of-stream marker and has to restart from scratch
+//! whenever its output buffer turns out to be too small. Driving the stream
+//! directly lets us grow the output in place and carry on into any streams
+//! that follow the first one.
+
+use crate::bzstream::{self, Step};
+use crate::control::{Control, Counters, STEP};
+use crate::scan::HEADER_LEN;
+use libbz2_rs_sys::{
+    bz_stream, BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit, BZ_DATA_ERROR_MAGIC,
+    BZ_OK, BZ_STREAM_END, BZ_UNEXPECTED_EOF,
+};
+
+/// Returned in place of a libbz2 status code when decoding would exceed the
+/// caller's [`Limits`]. libbz2 itself only uses codes in `-1..=-9`.
+pub const OUTPUT_LIMIT_EXCEEDED: i32 = -100;
+
+/// Returned when the output does not come out at the size the caller
+/// insisted on with [`Expected::strict`].
+pub const SIZE_MISMATCH: i32 = -102;
+
+/// Smallest output buffer we start decoding into.
+const MIN_OUTPUT_SIZE: usize = 4096;
+
+/// Caller-supplied bounds on how much output a decode may produce.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_output_size: Option<u64>,
+    /// Maximum number of output bytes per input byte.
+    pub max_ratio: Option<f64>,
+}
+
+impl Limits {
+    /// Largest output allowed for `input_len` bytes of input.
+    pub fn max_output(&self, input_len: u64) -> u64 {
+        let by_ratio = self
+            .max_ratio
+            .map_or(u64::MAX, |ratio| (input_len as f64 * ratio) as u64);
+        self.max_output_size.unwrap_or(u64::MAX).min(by_ratio)
+    }
+}
+
+/// A size hint is trusted for at most this many bytes of output per byte of
+/// input, and never for more than [`MAX_HINTED_OUTPUT`], so that a wrong one
+/// cannot make us allocate far more than the input decodes to. Past that the
+/// buffer grows from there as it would without a hint.
+const MAX_HINTED_RATIO: usize = 1024;
+const MAX_HINTED_OUTPUT: usize = 1 << 30;
+
+/// Output size the caller expects, typically from their own metadata.
+#[derive(Clone, Copy, Default)]
+pub struct Expected {
+    pub size: Option<usize>,
+    /// Whether any other output size is an error rather than a wrong guess.
+    pub strict: bool,
+}
+
+impl Expected {
+    /// Most output that can be produced without failing the check.
+    fn max_output(&self) -> usize {
+        match (self.size, self.strict) {
+            (Some(size), true) => size,
+            _ => usize::MAX,
+        }
+    }
+
+    /// Size of the first output buffer for `input_len` bytes of input, if
+    /// there is a hint.
+    fn initial_output(&self, input_len: usize) -> Option<usize> {
+        let bound = input_len
+            .saturating_mul(MAX_HINTED_RATIO)
+            .clamp(MIN_OUTPUT_SIZE, MAX_HINTED_OUTPUT);
+        self.size.map(|size| size.min(bound))
+    }
+
+    /// Checks the size of the complete output.
+    pub fn check(&self, len: usize) -> Result<(), i32> {
+        match (self.size, self.strict) {
+            (Some(size), true) if len != size => Err(SIZE_MISMATCH),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Owns an initialized decompression `bz_stream` and tears it down on drop.
+pub struct Decoder {
+    stream: Box<bz_stream>,
+    small: bool,
+    initialized: bool,
+}
+
+impl Decoder {
+    pub fn new(small: bool) -> Result<Self, i32> {
+        let mut decoder = Self {
+            stream: bzstream::new_stream(),
+            small,
+            initialized: false,
+        };
+        decoder.init()?;
+        Ok(decoder)
+    }
+
+    fn init(&mut self) -> Result<(), i32> {
+        let result =
+            unsafe { BZ2_bzDecompressInit(&mut *self.stream, 0, if self.small { 1 } else { 0 }) };
+        if result == BZ_OK {
+            self.initialized = true;
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
+    fn end(&mut self) {
+        if self.initialized {
+            unsafe {
+                BZ2_bzDecompressEnd(&mut *self.stream);
+            }
+            self.initialized = false;
+        }
+    }
+
+    /// Drops any state left over from the previous stream so the next call
+    /// starts by expecting a fresh `BZh` header.
+    pub fn reset(&mut self) -> Result<(), i32> {
+        self.end();
+        self.stream = bzstream::new_stream();
+        self.init()
+    }
+
+    /// Decodes as much of `input` into `output` as one call allows. Slices
+    /// longer than [`bzstream::WINDOW`] are only partially consumed or filled,
+    /// so callers loop until they see the progress they need.
+    pub fn step(&mut self, input: &[u8], output: &mut [u8]) -> Step {
+        bzstream::set_windows(&mut self.stream, input, output);
+        let code = unsafe { BZ2_bzDecompress(&mut *self.stream) };
+        Step::new(code, &self.stream, input, output)
+    }
+
+    /// Full 64-bit `(total_in, total_out)` counters of the current stream.
+    pub fn totals(&self) -> (u64, u64) {
+        bzstream::totals(&self.stream)
+    }
+}
+
+// The raw pointers in `bz_stream` are only ever touched through `&mut self`.
+unsafe impl Send for Decoder {}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+pub struct Decoded {
+    pub data: Vec<u8>,
+    /// Number of input bytes that belong to the decoded streams. Everything
+    /// from this offset on was left untouched.
+    pub consumed: usize,
+}
+
+/// One-shot decoding of a whole input, done a step at a time so that the
+/// caller can stop in between and carry on later. See [`decompress`].
+pub struct Decompression {
+    decoder: Decoder,
+    multi_stream: bool,
+    max_output: usize,
+    expected: Expected,
+    output: Vec<u8>,
+    filled: usize,
+    /// Size of each buffer in chunked mode, where a full `output` is set
+    /// aside in `chunks` rather than grown.
+    chunk_size: Option<usize>,
+    chunks: Vec<Vec<u8>>,
+    /// Bytes in `chunks`.
+    chunked: usize,
+    pos: usize,
+    stream_start: usize,
+    streams: usize,
+    counters: Counters,
+}
+
+impl Decompression {
+    /// Decodes into a single buffer, or into buffers of `chunk_size` bytes
+    /// each if given. Chunks never have to be copied to grow, so peak memory
+    /// stays close to the size of the output.
+    ///
+    /// A single buffer starts out at the `expected` size if there is one and
+    /// it is plausible for the input, so that a correct guess never has to
+    /// grow it. Otherwise it is a guess based on the input size.
+    pub fn new(
+        input_len: usize,
+        small: bool,
+        multi_stream: bool,
+        limits: Limits,
+        expected: Expected,
+        chunk_size: Option<usize>,
+    ) -> Result<Self, i32> {
+        let max_output = usize::try_from(limits.max_output(input_len as u64)).unwrap_or(usize::MAX);
+        let initial = chunk_size
+            .or(expected.initial_output(input_len))
+            .unwrap_or(input_len.saturating_mul(4).max(MIN_OUTPUT_SIZE));
+        let max_buffer = max_output.min(expected.max_output()).saturating_add(1);
+        Ok(Self {
+            decoder: Decoder::new(small)?,
+            multi_stream,
+            max_output,
+            expected,
+            output: vec![0u8; initial.min(max_buffer)],
+            filled: 0,
+            chunk_size,
+            chunks: Vec::new(),
+            chunked: 0,
+            pos: 0,
+            stream_start: 0,
+            streams: 0,
+            counters: Counters::default(),
+        })
+    }
+
+    /// Decodes at most `window` more bytes of output from `input`, which must
+    /// be the same slice on every call, and returns `true` once done.
+    pub fn step(&mut self, input: &[u8], window: usize, control: &Control) -> Result<bool, i32> {
+        control.check()?;
+        if self.filled == self.output.len() {
+            // One byte of headroom past the limit is enough to tell "exactly
+            // at the limit" apart from "would have produced more".
+            let max_buffer = self
+                .max_output
+                .min(self.expected.max_output())
+                .saturating_add(1);
+            match self.chunk_size {
+                Some(chunk_size) => {
+                    self.chunked += self.filled;
+                    let len = chunk_size.min(max_buffer - self.chunked);
+                    self.chunks
+                        .push(std::mem::replace(&mut self.output, vec![0u8; len]));
+                    self.filled = 0;
+                }
+                None => {
+                    let len = self
+                        .output
+                        .len()
+                        .saturating_mul(2)
+                        .max(MIN_OUTPUT_SIZE)
+                        .min(max_buffer);
+                    self.output.resize(len, 0u8);
+                }
+            }
+        }
+
+        let end = self.output.len().min(self.filled + window);
+        let step = self
+            .decoder
+            .step(&input[self.pos..], &mut self.output[self.filled..end]);
+        self.pos += step.consumed;
+        self.filled += step.produced;
+        self.counters.update(control, self.decoder.totals());
+
+        let produced = self.chunked + self.filled;
+        if produced > self.max_output {
+            return Err(OUTPUT_LIMIT_EXCEEDED);
+        }
+        if produced > self.expected.max_output() {
+            return Err(SIZE_MISMATCH);
+        }
+
+        match step.code {
+            BZ_OK => {
+                if self.pos == input.len() && !step.output_full {
+                    return Err(BZ_UNEXPECTED_EOF);
+                }
+                Ok(false)
+            }
+            BZ_STREAM_END => {
+                self.streams += 1;
+                // Too little is left for another stream to even start, so
+                // it is trailing garbage like any other.
+                if !self.multi_stream || input.len() - self.pos < HEADER_LEN {
+                    self.expected.check(produced)?;
+                    return Ok(true);
+                }
+                self.stream_start = self.pos;
+                self.decoder.reset()?;
+                self.counters = Counters::default();
+                Ok(false)
+            }
+            BZ_DATA_ERROR_MAGIC if self.streams > 0 => {
+                self.pos = self.stream_start;
+                self.expected.check(produced)?;
+                Ok(true)
+            }
+            code => Err(code),
+        }
+    }
+
+    /// The output of a decode without a chunk size.
+    pub fn into_decoded(mut self) -> Decoded {
+        self.output.truncate(self.filled);
+        Decoded {
+            data: self.output,
+            consumed: self.pos,
+        }
+    }
+
+    /// The output of a decode with a chunk size, and the number of input
+    /// bytes consumed as in [`Decoded`]. Every chunk but the last is full.
+    pub fn into_chunks(mut self) -> (Vec<Vec<u8>>, usize) {
+        if self.filled > 0 {
+            self.output.truncate(self.filled);
+            self.chunks.push(self.output);
+        }
+        (self.chunks, self.pos)
+    }
+}
+
+/// Decodes `input` into a single buffer.
+///
+/// With `multi_stream` set, decoding continues into every stream that follows
+/// the first one, the way `bzip2 -d` handles concatenated files. Anything after
+/// the last stream that does not start with a valid header is left alone, again
+/// matching the command-line tool.
+///
+/// Fails with [`OUTPUT_LIMIT_EXCEEDED`] as soon as the output grows past what
+/// `limits` allow, without ever allocating much more than that.
+pub fn decompress(
+    input: &[u8],
+    small: bool,
+    multi_stream: bool,
+    limits: Limits,
+    expected: Expected,
+    control: &Control,
+) -> Result<Decoded, i32> {
+    let mut decompression =
+        Decompression::new(input.len(), small, multi_stream, limits, expected, None)?;
+    while !decompression.step(input, STEP, control)? {}
+    Ok(decompression.into_decoded())
+}
+
+pub struct Tested {
+    pub uncompressed_size: u64,
+    pub streams: usize,
+    /// Number of input bytes that belong to the decoded streams.
+    pub consumed: usize,
+}
+
+/// Decodes every stream in `input` and throws the output away, the way
+/// `bzip2 -t` does. libbz2 checks each block CRC and the combined stream CRC
+/// as it goes, so getting to the end means the data is intact.
+pub fn test(input: &[u8], small: bool) -> Result<Tested, i32> {
+    let mut decoder = Decoder::new(small)?;
+    let mut scratch = vec![0u8; 64 * 1024];
+    let mut uncompressed_size = 0u64;
+    let mut pos = 0;
+    let mut stream_start = 0;
+    let mut streams = 0;
+
+    loop {
+        let step = decoder.step(&input[pos..], &mut scratch);
+        pos += step.consumed;
+        uncompressed_size += step.produced as u64;
+
+        match step.code {
+            BZ_OK => {
+                if pos == input.len() && !step.output_full {
+                    return Err(BZ_UNEXPECTED_EOF);
+                }
+            }
+            BZ_STREAM_END => {
+                streams += 1;
+                // As in `Decompression::step`.
+                if input.len() - pos < HEADER_LEN {
+                    break;
+                }
+                stream_start = pos;
+                decoder.reset()?;
+            }
+            BZ_DATA_ERROR_MAGIC if streams > 0 => {
+                pos = stream_start;
+                break;
+            }
+            code => return Err(code),
+        }
+    }
+
+    Ok(Tested {
+        uncompressed_size,
+        streams,
+        consumed: pos,
+    })
+}
@@ -0,0 +1,90 @@
+//! Hands NIF work over to a dirty CPU scheduler.
+//!
+//! Inputs small enough to be done with well within a timeslice are handled
+//! right in the NIF call. Anything larger may complete a block, and a single
+//! `BZ2_bzCompress` or `BZ2_bzDecompress` call that does so sorts or decodes
+//! the whole block, which can take over a hundred milliseconds at block size
+//! 9. No input window bounds that, so slicing the work and yielding with
+//! `enif_consume_timeslice` would not keep such calls off a normal scheduler.
+//! Instead [`run`] reschedules the NIF with `enif_schedule_nif` to do all of
+//! it on a dirty CPU scheduler. Unlike `schedule = "DirtyCpu"`, this leaves
+//! the small-input path on the calling process's own scheduler.
+
+use rustler::codegen_runtime::{c_int, NifReturnable, NIF_ENV, NIF_TERM};
+use rustler::sys::enif_schedule_nif;
+use rustler::{Encoder, Env, NifResult, ResourceArc, SchedulerFlags, Term};
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+/// Work to be done in one go by the rescheduled NIF call, given the term
+/// [`run`] was called with.
+pub type Job = Box<dyn for<'a> FnOnce(Env<'a>, Term<'a>) -> NifResult<Term<'a>> + Send>;
+
+/// Boxes `work` as a [`Job`].
+pub fn blocking<F>(work: F) -> Job
+where
+    F: for<'a> FnOnce(Env<'a>, Term<'a>) -> NifResult<Term<'a>> + Send + 'static,
+{
+    Box::new(work)
+}
+
+/// A job waiting to be picked up by the rescheduled NIF call.
+struct Parked {
+    job: Mutex<Option<Job>>,
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for Parked {}
+
+/// Runs `job` on a dirty CPU scheduler under the NIF `name`. `input` is kept
+/// alive until then and passed to it.
+pub fn run<'a>(
+    env: Env<'a>,
+    name: &'static CStr,
+    input: Term<'a>,
+    job: Job,
+) -> NifResult<Term<'a>> {
+    let parked = ResourceArc::new(Parked {
+        job: Mutex::new(Some(job)),
+    });
+    let args = [parked.encode(env).as_c_arg(), input.as_c_arg()];
+    unsafe {
+        let term = enif_schedule_nif(
+            env.as_c_arg(),
+            name.as_ptr(),
+            SchedulerFlags::DirtyCpu as c_int,
+            resume,
+            args.len() as c_int,
+            args.as_ptr(),
+        );
+        Ok(Term::new(env, term))
+    }
+}
+
+/// Takes the parked job and input back out of the rescheduled call's
+/// arguments and runs the job, converting its result the way generated NIF
+/// wrappers do.
+unsafe extern "C" fn resume(env: NIF_ENV, argc: c_int, argv: *const NIF_TERM) -> NIF_TERM {
+    let lifetime = ();
+    let env = Env::new(&lifetime, env);
+    let args = std::slice::from_raw_parts(argv, argc as usize);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let parked: ResourceArc<Parked> = Term::new(env, args[0]).decode()?;
+        let input = Term::new(env, args[1]);
+        let job = parked
+            .job
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(rustler::Error::BadArg)?;
+        job(env, input)
+    }));
+    match result {
+        Ok(result) => result.into_returned(env).apply(env),
+        Err(_) => rustler::Error::RaiseAtom("nif_panicked")
+            .into_returned(env)
+            .apply(env),
+    }
+}
@@ -0,0 +1,209 @@
+//! Incremental encoding on top of a raw `bz_stream`.
+//!
+//! `BZ2_bzBuffToBuffCompress` takes its lengths as `u32`, which silently
+//! truncates anything over 4 GiB. Driving the stream ourselves lets us feed
+//! input and collect output in [`bzstream::WINDOW`]-sized pieces instead.
+
+use crate::bzstream::{self, Step};
+use crate::control::{Control, Counters, STEP};
+use crate::iodata::Input;
+use libbz2_rs_sys::{
+    bz_stream, BZ2_bzCompress, BZ2_bzCompressEnd, BZ2_bzCompressInit, BZ_FINISH, BZ_FINISH_OK,
+    BZ_OK, BZ_OUTBUFF_FULL, BZ_RUN, BZ_RUN_OK, BZ_STREAM_END,
+};
+
+/// Worst-case compressed size of `len` input bytes, as documented for
+/// `BZ2_bzBuffToBuffCompress`.
+pub fn max_compressed_size(len: usize) -> usize {
+    len + len / 100 + 600
+}
+
+/// Owns an initialized compression `bz_stream` and tears it down on drop.
+pub struct Encoder {
+    stream: Box<bz_stream>,
+}
+
+impl Encoder {
+    pub fn new(block_size: i32, work_factor: i32) -> Result<Self, i32> {
+        let mut stream = bzstream::new_stream();
+        let result = unsafe { BZ2_bzCompressInit(&mut *stream, block_size, 0, work_factor) };
+        if result == BZ_OK {
+            Ok(Self { stream })
+        } else {
+            Err(result)
+        }
+    }
+
+    /// Runs one `BZ2_bzCompress` call with `action`. As with
+    /// [`Decoder::step`](crate::decoder::Decoder::step), only the first
+    /// [`bzstream::WINDOW`] bytes of each slice are visible to libbz2.
+    ///
+    /// libbz2 refuses a `BZ_FINISH` whose input differs from what was left
+    /// over by the previous one, so `BZ_FINISH` must not be issued until the
+    /// remaining input fits in a single window.
+    pub fn step(&mut self, input: &[u8], output: &mut [u8], action: i32) -> Step {
+        bzstream::set_windows(&mut self.stream, input, output);
+        let code = unsafe { BZ2_bzCompress(&mut *self.stream, action) };
+        Step::new(code, &self.stream, input, output)
+    }
+
+    /// Full 64-bit `(total_in, total_out)` counters of the stream.
+    pub fn totals(&self) -> (u64, u64) {
+        bzstream::totals(&self.stream)
+    }
+}
+
+// The raw pointers in `bz_stream` are only ever touched through `&mut self`.
+unsafe impl Send for Encoder {}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            BZ2_bzCompressEnd(&mut *self.stream);
+        }
+    }
+}
+
+/// Feeds all of `input` through `encoder` with `BZ_RUN`, appending whatever
+/// it writes to `output`.
+pub fn run(
+    encoder: &mut Encoder,
+    input: &[u8],
+    output: &mut Vec<u8>,
+    control: &Control,
+) -> Result<(), i32> {
+    let mut pos = 0;
+    let mut filled = output.len();
+    output.resize(filled + max_compressed_size(input.len()), 0u8);
+
+    loop {
+        control.check()?;
+        if filled == output.len() {
+            output.resize(output.len() * 2, 0u8);
+        }
+
+        let end = input.len().min(pos + STEP);
+        let step = encoder.step(&input[pos..end], &mut output[filled..], BZ_RUN);
+        pos += step.consumed;
+        filled += step.produced;
+
+        if step.code != BZ_RUN_OK {
+            return Err(step.code);
+        }
+        if pos == input.len() && !step.output_full {
+            break;
+        }
+    }
+
+    output.truncate(filled);
+    Ok(())
+}
+
+/// Flushes `encoder` with `BZ_FINISH`, appending the end of the stream to
+/// `output`.
+pub fn finish(encoder: &mut Encoder, output: &mut Vec<u8>, control: &Control) -> Result<(), i32> {
+    let mut filled = output.len();
+    output.resize(filled + 4096, 0u8);
+
+    loop {
+        control.check()?;
+        if filled == output.len() {
+            output.resize(output.len() * 2, 0u8);
+        }
+
+        let step = encoder.step(&[], &mut output[filled..], BZ_FINISH);
+        filled += step.produced;
+
+        match step.code {
+            BZ_FINISH_OK => {}
+            BZ_STREAM_END => break,
+            code => return Err(code),
+        }
+    }
+
+    output.truncate(filled);
+    Ok(())
+}
+
+/// One-shot compression of a whole input, done a step at a time so that the
+/// caller can stop in between and carry on later.
+pub struct Compression {
+    encoder: Encoder,
+    output: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    counters: Counters,
+}
+
+impl Compression {
+    pub fn new(input_len: usize, block_size: i32, work_factor: i32) -> Result<Self, i32> {
+        Ok(Self {
+            encoder: Encoder::new(block_size, work_factor)?,
+            output: vec![0u8; max_compressed_size(input_len)],
+            pos: 0,
+            filled: 0,
+            counters: Counters::default(),
+        })
+    }
+
+    /// Feeds at most `window` more bytes of `input`, which must be the same
+    /// input on every call, and returns `true` once the stream is complete.
+    pub fn step<I: Input + ?Sized>(
+        &mut self,
+        input: &I,
+        window: usize,
+        control: &Control,
+    ) -> Result<bool, i32> {
+        control.check()?;
+        if self.filled == self.output.len() {
+            return Err(BZ_OUTBUFF_FULL);
+        }
+
+        // Only the last window can use `BZ_FINISH`; see `Encoder::step`.
+        let chunk = input.chunk(self.pos, window);
+        let action = if chunk.len().min(bzstream::WINDOW) < input.len() - self.pos {
+            BZ_RUN
+        } else {
+            BZ_FINISH
+        };
+        let step = self
+            .encoder
+            .step(chunk, &mut self.output[self.filled..], action);
+        self.pos += step.consumed;
+        self.filled += step.produced;
+        self.counters.update(control, self.encoder.totals());
+
+        match step.code {
+            BZ_RUN_OK | BZ_FINISH_OK => Ok(false),
+            BZ_STREAM_END => Ok(true),
+            code => Err(code),
+        }
+    }
+
+    pub fn into_output(mut self) -> Vec<u8> {
+        self.output.truncate(self.filled);
+        self.output
+    }
+}
+
+/// Smallest block size whose single block holds `len` bytes of input. A
+/// block holds 100k × block size - 19 bytes after libbz2's initial run-length
+/// encoding, which grows the input by at most a quarter.
+pub fn smallest_block_size(len: usize) -> i32 {
+    let encoded = len
+        .saturating_add(len.saturating_add(3) / 4)
+        .saturating_add(19);
+    (encoded / 100_000 + 1).min(9) as i32
+}
+
+/// Compresses `input` into a single complete stream.
+pub fn compress<I: Input + ?Sized>(
+    input: &I,
+    block_size: i32,
+    work_factor: i32,
+    control: &Control,
+) -> Result<Vec<u8>, i32> {
+    let mut compression = Compression::new(input.len(), block_size, work_factor)?;
+    while !compression.step(input, STEP, control)? {}
+    Ok(compression.into_output())
+}
@@ -0,0 +1,334 @@
+//! Block index for random access into bzip2 data.
+//!
+//! Blocks are independent of each other apart from the combined CRC at the end
+//! of their stream, so any block can be decoded on its own once we know where
+//! it starts and ends. libbz2 has no entry point for that, so [`decode_block`]
+//! copies the block bits into a synthetic one-block stream and decodes that
+//! instead. With a single block the combined CRC is just the block CRC.
+
+use crate::bits::{bit_len, read_bits, BitWriter};
+use crate::control::Control;
+use crate::decoder::{self, Expected, Limits};
+use crate::scan::{self, EOS_MAGIC};
+use libbz2_rs_sys::{BZ_DATA_ERROR, BZ_UNEXPECTED_EOF};
+
+pub struct Entry {
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    /// Offset of the block's first byte in the decompressed data.
+    pub uncompressed_offset: u64,
+    pub crc: u32,
+}
+
+impl Entry {
+    /// Bit offset just past the block, unless a corrupt index makes it
+    /// overflow.
+    fn bit_end(&self) -> Option<u64> {
+        self.bit_offset.checked_add(self.bit_length)
+    }
+}
+
+pub struct Index {
+    /// Largest block size digit of any stream. Every block decodes fine with
+    /// a header at least this large.
+    pub level: u8,
+    pub entries: Vec<Entry>,
+    pub uncompressed_size: u64,
+}
+
+/// Why a serialized index was rejected.
+pub enum FormatError {
+    /// Bad magic, bad checksum, truncated or internally inconsistent.
+    Invalid,
+    UnsupportedVersion,
+}
+
+/// Leading bytes of a serialized index.
+const FORMAT_MAGIC: &[u8; 4] = b"BZIX";
+const FORMAT_VERSION: u8 = 1;
+/// Magic, version, level, two reserved bytes, block count, uncompressed size.
+const FORMAT_HEADER_LEN: usize = 4 + 1 + 1 + 2 + 8 + 8;
+/// Bit offset, bit length, uncompressed offset, CRC.
+const FORMAT_ENTRY_LEN: usize = 8 + 8 + 8 + 4;
+const FORMAT_CHECKSUM_LEN: usize = 4;
+
+/// Block magic and block CRC, the least any block is made of.
+const BLOCK_HEADER_BITS: u64 = 48 + 32;
+
+/// Returned in place of a libbz2 status code by [`read`] when an entry of
+/// the index points past the end of the data.
+pub const INVALID_INDEX: i32 = -103;
+
+/// CRC-32 with the polynomial and bit order bzip2 uses for its own blocks.
+fn checksum(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = (i as u32) << 24;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (crc << 8) ^ TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+impl Index {
+    /// Serializes the index into the sidecar format:
+    ///
+    /// ```text
+    /// "BZIX" | version: u8 | level: u8 | reserved: u16
+    /// block count: u64 | uncompressed size: u64
+    /// per block: bit offset: u64 | bit length: u64 | uncompressed offset: u64 | crc: u32
+    /// checksum: u32
+    /// ```
+    ///
+    /// All integers are big-endian. The checksum is the bzip2 CRC-32 of every
+    /// byte before it.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            FORMAT_HEADER_LEN + self.entries.len() * FORMAT_ENTRY_LEN + FORMAT_CHECKSUM_LEN,
+        );
+        out.extend_from_slice(FORMAT_MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(self.level);
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&(self.entries.len() as u64).to_be_bytes());
+        out.extend_from_slice(&self.uncompressed_size.to_be_bytes());
+        for entry in &self.entries {
+            out.extend_from_slice(&entry.bit_offset.to_be_bytes());
+            out.extend_from_slice(&entry.bit_length.to_be_bytes());
+            out.extend_from_slice(&entry.uncompressed_offset.to_be_bytes());
+            out.extend_from_slice(&entry.crc.to_be_bytes());
+        }
+        out.extend_from_slice(&checksum(&out).to_be_bytes());
+        out
+    }
+
+    /// Parses an index written by [`Index::encode`].
+    pub fn decode(data: &[u8]) -> Result<Self, FormatError> {
+        fn u64_at(data: &[u8], at: usize) -> u64 {
+            u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+        }
+
+        if data.len() < FORMAT_HEADER_LEN + FORMAT_CHECKSUM_LEN || &data[..4] != FORMAT_MAGIC {
+            return Err(FormatError::Invalid);
+        }
+        if data[4] != FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion);
+        }
+
+        let (body, stored) = data.split_at(data.len() - FORMAT_CHECKSUM_LEN);
+        if checksum(body).to_be_bytes() != stored {
+            return Err(FormatError::Invalid);
+        }
+
+        let level = body[5];
+        let count = u64_at(body, 8);
+        let uncompressed_size = u64_at(body, 16);
+        let entries_len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(FORMAT_ENTRY_LEN))
+            .ok_or(FormatError::Invalid)?;
+        if !(1..=9).contains(&level) || body.len() != FORMAT_HEADER_LEN + entries_len {
+            return Err(FormatError::Invalid);
+        }
+
+        let entries: Vec<Entry> = body[FORMAT_HEADER_LEN..]
+            .chunks_exact(FORMAT_ENTRY_LEN)
+            .map(|chunk| Entry {
+                bit_offset: u64_at(chunk, 0),
+                bit_length: u64_at(chunk, 8),
+                uncompressed_offset: u64_at(chunk, 16),
+                crc: u32::from_be_bytes(chunk[24..28].try_into().unwrap()),
+            })
+            .collect();
+
+        // `read` relies on blocks being in order, non-empty and not
+        // overlapping in the compressed data either.
+        let ordered = entries.windows(2).all(|pair| {
+            pair[0].uncompressed_offset < pair[1].uncompressed_offset
+                && pair[0]
+                    .bit_end()
+                    .is_some_and(|end| end <= pair[1].bit_offset)
+        });
+        let well_formed = entries
+            .iter()
+            .all(|entry| entry.bit_length >= BLOCK_HEADER_BITS && entry.bit_end().is_some());
+        let first_at_zero = entries
+            .first()
+            .is_none_or(|entry| entry.uncompressed_offset == 0);
+        let last_in_range = entries
+            .last()
+            .is_none_or(|entry| entry.uncompressed_offset < uncompressed_size);
+        if !ordered
+            || !well_formed
+            || !first_at_zero
+            || !last_in_range
+            || (entries.is_empty() && uncompressed_size != 0)
+        {
+            return Err(FormatError::Invalid);
+        }
+
+        Ok(Self {
+            level,
+            entries,
+            uncompressed_size,
+        })
+    }
+
+    /// Uncompressed size of the block at `i`.
+    pub fn block_size(&self, i: usize) -> u64 {
+        let end = self
+            .entries
+            .get(i + 1)
+            .map_or(self.uncompressed_size, |next| next.uncompressed_offset);
+        end - self.entries[i].uncompressed_offset
+    }
+}
+
+/// Decodes the block at `bit_offset..bit_offset + bit_length` of `data` by
+/// wrapping it in a stream of its own.
+pub fn decode_block(
+    data: &[u8],
+    bit_offset: u64,
+    bit_length: u64,
+    level: u8,
+    small: bool,
+    control: &Control,
+) -> Result<Vec<u8>, i32> {
+    if bit_offset
+        .checked_add(bit_length)
+        .is_none_or(|end| end > bit_len(data))
+    {
+        return Err(BZ_UNEXPECTED_EOF);
+    }
+    let crc = read_bits(data, bit_offset + 48, 32);
+
+    let mut writer = BitWriter::new();
+    for byte in [b'B', b'Z', b'h', b'0' + level] {
+        writer.write(u64::from(byte), 8);
+    }
+    writer.copy_bits(data, bit_offset, bit_offset + bit_length);
+    writer.write(EOS_MAGIC >> 24, 24);
+    writer.write(EOS_MAGIC, 24);
+    writer.write(crc, 32);
+
+    let limits = Limits {
+        max_output_size: None,
+        max_ratio: None,
+    };
+    decoder::decompress(
+        &writer.finish(),
+        small,
+        false,
+        limits,
+        Expected::default(),
+        control,
+    )
+    .map(|decoded| decoded.data)
+}
+
+/// Scans `data` and decodes every block once to learn where it lands in the
+/// decompressed output.
+pub fn build(data: &[u8], small: bool) -> Result<Index, i32> {
+    let streams = scan::scan(data)?;
+    let level = streams.iter().map(|stream| stream.level).max().unwrap_or(9);
+
+    let mut entries = Vec::new();
+    let mut uncompressed_offset = 0u64;
+    for stream in &streams {
+        for block in &stream.blocks {
+            let decoded = decode_block(
+                data,
+                block.bit_offset,
+                block.bit_length,
+                level,
+                small,
+                &Control::default(),
+            )?;
+            entries.push(Entry {
+                bit_offset: block.bit_offset,
+                bit_length: block.bit_length,
+                uncompressed_offset,
+                crc: block.crc,
+            });
+            uncompressed_offset += decoded.len() as u64;
+        }
+    }
+
+    Ok(Index {
+        level,
+        entries,
+        uncompressed_size: uncompressed_offset,
+    })
+}
+
+/// Decompresses `length` bytes starting at `offset` of the uncompressed data,
+/// decoding only the blocks that cover that range. Like `pread`, a range that
+/// runs past the end is cut short.
+pub fn read(
+    data: &[u8],
+    index: &Index,
+    offset: u64,
+    length: u64,
+    small: bool,
+) -> Result<Vec<u8>, i32> {
+    let end = offset.saturating_add(length).min(index.uncompressed_size);
+    if offset >= end {
+        return Ok(Vec::new());
+    }
+
+    let first = index
+        .entries
+        .partition_point(|entry| entry.uncompressed_offset <= offset)
+        - 1;
+    // The range comes from the caller and the sizes from the index, so
+    // neither is trusted to size the output up front.
+    let mut output = Vec::new();
+
+    for (i, entry) in index.entries.iter().enumerate().skip(first) {
+        if entry.uncompressed_offset >= end {
+            break;
+        }
+        if entry.bit_end().is_none_or(|end| end > bit_len(data)) {
+            return Err(INVALID_INDEX);
+        }
+        // Catch an index being used with the wrong data before libbz2 gets to
+        // decode garbage.
+        if read_bits(data, entry.bit_offset + 48, 32) as u32 != entry.crc {
+            return Err(BZ_DATA_ERROR);
+        }
+
+        let block = decode_block(
+            data,
+            entry.bit_offset,
+            entry.bit_length,
+            index.level,
+            small,
+            &Control::default(),
+        )?;
+        if block.len() as u64 != index.block_size(i) {
+            return Err(BZ_DATA_ERROR);
+        }
+
+        let from = offset.saturating_sub(entry.uncompressed_offset) as usize;
+        let to = (end - entry.uncompressed_offset).min(block.len() as u64) as usize;
+        output.extend_from_slice(&block[from..to]);
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,228 @@
+//! Compression input given as iodata.
+//!
+//! Callers often build their payload as an iolist. Flattening it with
+//! `IO.iodata_to_binary/1` or `enif_inspect_iolist_as_binary` copies every
+//! byte once more before libbz2 gets to see it, so [`IoData`] walks the list
+//! instead and keeps one segment per binary, which the encoder is then fed
+//! from one after the other.
+
+use rustler::{Binary, Error, NifResult, OwnedEnv, Term};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// The `length` bytes from `offset` on, or everything from `offset` on, of
+/// an input of `len` bytes. A range that does not lie within the input is a
+/// `badarg`.
+pub fn range(len: usize, offset: usize, length: Option<usize>) -> NifResult<Range<usize>> {
+    let available = len.checked_sub(offset).ok_or(Error::BadArg)?;
+    match length {
+        Some(length) if length > available => Err(Error::BadArg),
+        Some(length) => Ok(offset..offset + length),
+        None => Ok(offset..len),
+    }
+}
+
+/// Input that may be split over several segments.
+pub trait Input {
+    fn len(&self) -> usize;
+
+    /// Contiguous bytes starting at `pos`, at most `max` of them. Only empty
+    /// once `pos` has reached the end.
+    fn chunk(&self, pos: usize, max: usize) -> &[u8];
+
+    /// The whole input as one slice, which is only free when it is in one
+    /// piece to begin with.
+    fn to_contiguous(&self) -> Cow<'_, [u8]>;
+}
+
+impl Input for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn chunk(&self, pos: usize, max: usize) -> &[u8] {
+        &self[pos..self.len().min(pos.saturating_add(max))]
+    }
+
+    fn to_contiguous(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+enum Segment<'a> {
+    /// A binary in the list.
+    Binary(&'a [u8]),
+    /// Consecutive integer elements of the list.
+    Bytes(Vec<u8>),
+}
+
+impl Segment<'_> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Segment::Binary(binary) => binary,
+            Segment::Bytes(bytes) => bytes,
+        }
+    }
+
+    fn slice(self, range: Range<usize>) -> Self {
+        match self {
+            Segment::Binary(binary) => Segment::Binary(&binary[range]),
+            Segment::Bytes(bytes) => Segment::Bytes(bytes[range].to_vec()),
+        }
+    }
+}
+
+/// The segments of an iolist, borrowed from the term for the duration of
+/// the NIF call. Work that goes on in a rescheduled call walks the term it
+/// is passed again rather than holding on to one of these, so nothing is
+/// copied out of the process heap.
+pub struct IoData<'a> {
+    segments: Vec<Segment<'a>>,
+    /// Offset of each segment in the whole input.
+    offsets: Vec<usize>,
+    len: usize,
+}
+
+impl<'a> IoData<'a> {
+    /// Walks `term`, which must be a binary or an iolist. Anything else is a
+    /// `badarg`.
+    pub fn new(term: Term<'a>) -> NifResult<Self> {
+        let mut segments = Vec::new();
+        // Terms still to visit, and whether each is the head of a list
+        // cell, which is the only place a byte may appear.
+        let mut pending = vec![(term, false)];
+        while let Some((term, head)) = pending.pop() {
+            if term.is_binary() {
+                let binary = Binary::from_term(term)?;
+                if !binary.is_empty() {
+                    segments.push(Segment::Binary(binary.as_slice()));
+                }
+            } else if term.is_list() {
+                if let Ok((head, tail)) = term.list_get_cell() {
+                    pending.push((tail, false));
+                    pending.push((head, true));
+                }
+            } else if head {
+                let byte: u8 = term.decode()?;
+                match segments.last_mut() {
+                    Some(Segment::Bytes(bytes)) => bytes.push(byte),
+                    _ => segments.push(Segment::Bytes(vec![byte])),
+                }
+            } else {
+                return Err(Error::BadArg);
+            }
+        }
+
+        let mut data = Self {
+            segments: Vec::new(),
+            offsets: Vec::new(),
+            len: 0,
+        };
+        data.set_segments(segments);
+        Ok(data)
+    }
+
+    fn set_segments(&mut self, segments: Vec<Segment<'a>>) {
+        self.offsets.clear();
+        self.len = 0;
+        for segment in &segments {
+            self.offsets.push(self.len);
+            self.len += segment.as_slice().len();
+        }
+        self.segments = segments;
+    }
+
+    /// Narrows the input down to the bytes in `range`, as returned by
+    /// [`range`].
+    pub fn select(&mut self, range: Range<usize>) {
+        let mut segments = Vec::new();
+        for (segment, offset) in std::mem::take(&mut self.segments)
+            .into_iter()
+            .zip(&self.offsets)
+        {
+            let end = offset + segment.as_slice().len();
+            let start = range.start.max(*offset);
+            if start < end.min(range.end) {
+                segments.push(segment.slice(start - offset..end.min(range.end) - offset));
+            }
+        }
+        self.set_segments(segments);
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments.iter().map(Segment::as_slice)
+    }
+}
+
+impl Input for IoData<'_> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn chunk(&self, pos: usize, max: usize) -> &[u8] {
+        if pos >= self.len {
+            return &[];
+        }
+        let i = self.offsets.partition_point(|&offset| offset <= pos) - 1;
+        let segment = self.segments[i].as_slice();
+        let start = pos - self.offsets[i];
+        &segment[start..segment.len().min(start.saturating_add(max))]
+    }
+
+    fn to_contiguous(&self) -> Cow<'_, [u8]> {
+        match self.segments.as_slice() {
+            [] => Cow::Borrowed(&[]),
+            [segment] => Cow::Borrowed(segment.as_slice()),
+            _ => Cow::Owned(self.segments().collect::<Vec<_>>().concat()),
+        }
+    }
+}
+
+/// The binaries of a list, for the batch NIFs. They are held by a copy of
+/// the term in a process-independent environment, where garbage collection
+/// does not move them, so jobs may keep them across rescheduled NIF calls
+/// and hand them to worker threads. Copying only bumps the reference count
+/// of large binaries.
+pub struct Binaries {
+    binaries: Vec<(*const u8, usize)>,
+    _env: OwnedEnv,
+}
+
+// The binaries only point into `_env`, which moves along with them. They
+// are only ever read, so workers may share them too.
+unsafe impl Send for Binaries {}
+unsafe impl Sync for Binaries {}
+
+impl Binaries {
+    /// Decodes `term`, which must be a proper list of binaries.
+    pub fn new(term: Term) -> NifResult<Self> {
+        let env = OwnedEnv::new();
+        let saved = env.save(term);
+        let binaries = env.run(|env| {
+            saved.load(env).decode::<Vec<Binary>>().map(|binaries| {
+                binaries
+                    .iter()
+                    .map(|binary| (binary.as_ptr(), binary.len()))
+                    .collect()
+            })
+        })?;
+        Ok(Self {
+            binaries,
+            _env: env,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.binaries.len()
+    }
+
+    pub fn get(&self, i: usize) -> &[u8] {
+        let (ptr, len) = self.binaries[i];
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    }
+
+    /// Combined size of all binaries.
+    pub fn size(&self) -> usize {
+        self.binaries.iter().map(|(_, len)| len).sum()
+    }
+}
@@ -1,7 +1,26 @@
 //! Rustler NIF bindings for bzip2 compression using libbz2-rs-sys
 
-use rustler::{Atom, Binary, Env, NewBinary, NifResult, ResourceArc};
-use std::sync::Mutex;
+use iodata::{Input, IoData};
+use rustler::{
+    Atom, Binary, Encoder, Env, LocalPid, NewBinary, NifMap, NifResult, OwnedEnv, Reference,
+    ResourceArc, Term,
+};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod bits;
+mod bzstream;
+mod control;
+mod decoder;
+mod dirty;
+mod encoder;
+mod index;
+mod iodata;
+mod parallel;
+mod pipeline;
+mod pool;
+mod scan;
 
 mod atoms {
     rustler::atoms! {
@@ -16,7 +35,13 @@ mod atoms {
         unexpected_eof,
         outbuff_full,
         sequence_error,
+        output_limit_exceeded,
+        size_mismatch,
         unknown_error,
+        invalid_index,
+        unsupported_version,
+        cancelled,
+        bz2_progress,
         ready,
         finished,
     }
@@ -33,6 +58,10 @@ fn bz_error_to_atom(code: i32) -> Atom {
         libbz2_rs_sys::BZ_UNEXPECTED_EOF => atoms::unexpected_eof(),
         libbz2_rs_sys::BZ_OUTBUFF_FULL => atoms::outbuff_full(),
         libbz2_rs_sys::BZ_SEQUENCE_ERROR => atoms::sequence_error(),
+        decoder::OUTPUT_LIMIT_EXCEEDED => atoms::output_limit_exceeded(),
+        decoder::SIZE_MISMATCH => atoms::size_mismatch(),
+        control::CANCELLED => atoms::cancelled(),
+        index::INVALID_INDEX => atoms::invalid_index(),
         _ => atoms::unknown_error(),
     }
 }
@@ -41,635 +70,1175 @@ fn bz_error_to_atom(code: i32) -> Atom {
 // One-shot API
 // =============================================================================
 
-// i8 targets: Apple (all), x86_64-linux, FreeBSD (all), OpenBSD (all)
-#[cfg(any(
-    target_vendor = "apple",
-    all(target_arch = "x86_64", target_os = "linux"),
-    all(target_arch = "x86_64", target_os = "freebsd"),
-    all(target_arch = "aarch64", target_os = "freebsd"),
-    all(target_arch = "x86_64", target_os = "openbsd"),
-    all(target_arch = "aarch64", target_os = "openbsd"),
-))]
-#[rustler::nif(schedule = "DirtyCpu")]
-fn compress<'a>(
-    env: Env<'a>,
-    input: Binary<'a>,
+/// Options of `compress` and `compress_async`, as validated by `Bz2Ex`.
+#[derive(NifMap, Clone, Copy)]
+struct CompressOptions {
+    offset: usize,
+    length: Option<usize>,
+    /// The smallest one that fits the input if not given.
+    block_size: Option<i32>,
+    work_factor: i32,
+    threads: usize,
+    single_stream: bool,
+}
+
+/// Options of `decompress` and `decompress_async`, as validated by `Bz2Ex`.
+#[derive(NifMap, Clone, Copy)]
+struct DecompressOptions {
+    offset: usize,
+    length: Option<usize>,
+    small: bool,
+    multi_stream: bool,
+    max_output_size: Option<u64>,
+    max_ratio: Option<f64>,
+    expected_size: Option<usize>,
+    strict_size: bool,
+    threads: usize,
+}
+
+impl DecompressOptions {
+    fn limits(&self) -> decoder::Limits {
+        decoder::Limits {
+            max_output_size: self.max_output_size,
+            max_ratio: self.max_ratio,
+        }
+    }
+
+    fn expected(&self) -> decoder::Expected {
+        decoder::Expected {
+            size: self.expected_size,
+            strict: self.strict_size,
+        }
+    }
+}
+
+fn run_compress<I: Input + ?Sized>(
+    input: &I,
     block_size: i32,
     work_factor: i32,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let input_slice = input.as_slice();
-    let max_output_size = input_slice.len() + (input_slice.len() / 100) + 600;
-    let mut output_vec = vec![0u8; max_output_size];
-    let mut dest_len = max_output_size as u32;
-
-    let result = unsafe {
-        libbz2_rs_sys::BZ2_bzBuffToBuffCompress(
-            output_vec.as_mut_ptr() as *mut i8,
-            &mut dest_len,
-            input_slice.as_ptr() as *mut i8,
-            input_slice.len() as u32,
+    threads: usize,
+    single_stream: bool,
+    control: &control::Control,
+) -> Result<Vec<u8>, i32> {
+    if threads > 1 {
+        // Chunks are cut at arbitrary offsets, so the workers need the input
+        // in one piece.
+        parallel::compress(
+            &input.to_contiguous(),
             block_size,
-            0,
             work_factor,
+            threads,
+            single_stream,
+            control,
         )
-    };
+    } else {
+        encoder::compress(input, block_size, work_factor, control)
+    }
+}
 
-    if result == libbz2_rs_sys::BZ_OK {
-        output_vec.truncate(dest_len as usize);
-        let mut binary = NewBinary::new(env, dest_len as usize);
-        binary.as_mut_slice().copy_from_slice(&output_vec);
-        Ok((atoms::ok(), binary.into()))
+fn run_decompress(
+    input: &[u8],
+    small: bool,
+    multi_stream: bool,
+    limits: decoder::Limits,
+    expected: decoder::Expected,
+    threads: usize,
+    control: &control::Control,
+) -> Result<decoder::Decoded, i32> {
+    if threads > 1 {
+        let decoded = parallel::decompress(input, small, multi_stream, limits, threads, control)?;
+        expected.check(decoded.data.len())?;
+        Ok(decoded)
     } else {
-        let binary = NewBinary::new(env, 0);
-        Ok((bz_error_to_atom(result), binary.into()))
+        decoder::decompress(input, small, multi_stream, limits, expected, control)
     }
 }
 
-// u8 target: aarch64-linux only
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-#[rustler::nif(schedule = "DirtyCpu")]
-fn compress<'a>(
+/// Inputs up to this size are handled right away, without going through
+/// [`dirty`] or the worker pool. Compressing them takes well under a
+/// timeslice, so the bookkeeping would only add latency.
+const SMALL_INPUT: usize = 4096;
+
+/// Most output decoded from a small input before the rest is handed to a
+/// dirty scheduler.
+const SMALL_OUTPUT: usize = 64 * 1024;
+
+/// Largest block size digit of a small input that is decoded right away.
+/// However short the input, libbz2 decodes a whole block as soon as it
+/// produces the first byte of it, which takes well under a millisecond at
+/// block size 1 but several at block size 9.
+const SMALL_LEVEL: u8 = 1;
+
+/// Whether `input` starts with a stream of blocks small enough to decode on
+/// the spot. Input without a header fails right away.
+fn small_blocks(input: &[u8]) -> bool {
+    scan::parse_header(input, 0).is_none_or(|level| level <= SMALL_LEVEL)
+}
+
+/// An output buffer lent to the VM as the backing store of a binary.
+struct Output(Vec<u8>);
+
+#[rustler::resource_impl]
+impl rustler::Resource for Output {}
+
+/// Outputs up to this size are copied into a plain binary instead, which
+/// costs less than setting up a resource.
+const COPY_OUTPUT: usize = 4096;
+
+/// Turns `output` into a binary without copying it. Outputs are allocated
+/// for the worst case or grown by doubling, so the unused capacity is given
+/// back first, which the allocator can do in place.
+fn output_binary(env: Env, mut output: Vec<u8>) -> Binary {
+    if output.len() <= COPY_OUTPUT {
+        let mut binary = NewBinary::new(env, output.len());
+        binary.as_mut_slice().copy_from_slice(&output);
+        return binary.into();
+    }
+    output.shrink_to_fit();
+    ResourceArc::new(Output(output)).make_binary(env, |output| &output.0)
+}
+
+fn compressed_reply<'a>(env: Env<'a>, result: Result<Vec<u8>, i32>) -> Term<'a> {
+    match result {
+        Ok(output) => (atoms::ok(), output_binary(env, output)).encode(env),
+        Err(code) => {
+            let binary = NewBinary::new(env, 0);
+            (bz_error_to_atom(code), Binary::from(binary)).encode(env)
+        }
+    }
+}
+
+fn decompressed_reply<'a>(
     env: Env<'a>,
     input: Binary<'a>,
-    block_size: i32,
-    work_factor: i32,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let input_slice = input.as_slice();
-    let max_output_size = input_slice.len() + (input_slice.len() / 100) + 600;
-    let mut output_vec = vec![0u8; max_output_size];
-    let mut dest_len = max_output_size as u32;
-
-    let result = unsafe {
-        libbz2_rs_sys::BZ2_bzBuffToBuffCompress(
-            output_vec.as_mut_ptr(),
-            &mut dest_len,
-            input_slice.as_ptr() as *mut u8,
-            input_slice.len() as u32,
+    result: Result<decoder::Decoded, i32>,
+) -> NifResult<Term<'a>> {
+    match result {
+        Ok(decoded) => {
+            let rest = input.make_subbinary(decoded.consumed, input.len() - decoded.consumed)?;
+            Ok((atoms::ok(), output_binary(env, decoded.data), rest).encode(env))
+        }
+        Err(code) => {
+            let binary = NewBinary::new(env, 0);
+            let rest = NewBinary::new(env, 0);
+            Ok((
+                bz_error_to_atom(code),
+                Binary::from(binary),
+                Binary::from(rest),
+            )
+                .encode(env))
+        }
+    }
+}
+
+/// The part of `input` selected by `offset` and `length`, which is checked
+/// against its bounds. Making a sub-binary copies nothing.
+fn sub_binary(input: Binary, offset: usize, length: Option<usize>) -> NifResult<Binary> {
+    let range = iodata::range(input.len(), offset, length)?;
+    input.make_subbinary(range.start, range.len())
+}
+
+/// Like [`decompressed_reply`], with the output as a list of binaries made
+/// from `chunks`.
+fn chunks_reply<'a>(
+    env: Env<'a>,
+    input: Binary<'a>,
+    chunks: impl IntoIterator<Item = Vec<u8>>,
+    consumed: usize,
+) -> NifResult<Term<'a>> {
+    let list: Vec<Term> = chunks
+        .into_iter()
+        .map(|chunk| output_binary(env, chunk).to_term(env))
+        .collect();
+    let rest = input.make_subbinary(consumed, input.len() - consumed)?;
+    Ok((atoms::ok(), list, rest).encode(env))
+}
+
+/// Replies with the output of a finished `decompression`, which was set up
+/// with `chunk_size`.
+fn finished_reply<'a>(
+    env: Env<'a>,
+    input: Binary<'a>,
+    decompression: decoder::Decompression,
+    chunk_size: Option<usize>,
+) -> NifResult<Term<'a>> {
+    match chunk_size {
+        Some(_) => {
+            let (chunks, consumed) = decompression.into_chunks();
+            chunks_reply(env, input, chunks, consumed)
+        }
+        None => decompressed_reply(env, input, Ok(decompression.into_decoded())),
+    }
+}
+
+#[rustler::nif]
+fn compress<'a>(env: Env<'a>, term: Term<'a>, options: CompressOptions) -> NifResult<Term<'a>> {
+    let CompressOptions {
+        offset,
+        length,
+        block_size,
+        work_factor,
+        threads,
+        single_stream,
+    } = options;
+    let mut input = IoData::new(term)?;
+    let range = iodata::range(input.len(), offset, length)?;
+    input.select(range.clone());
+    let block_size = block_size.unwrap_or_else(|| encoder::smallest_block_size(input.len()));
+    // A single chunk comes out the same whether or not it went through the
+    // pool.
+    if input.len() <= SMALL_INPUT {
+        let result = encoder::compress(
+            &input,
             block_size,
-            0,
             work_factor,
-        )
-    };
-
-    if result == libbz2_rs_sys::BZ_OK {
-        output_vec.truncate(dest_len as usize);
-        let mut binary = NewBinary::new(env, dest_len as usize);
-        binary.as_mut_slice().copy_from_slice(&output_vec);
-        Ok((atoms::ok(), binary.into()))
-    } else {
-        let binary = NewBinary::new(env, 0);
-        Ok((bz_error_to_atom(result), binary.into()))
+            &control::Control::default(),
+        );
+        return Ok(compressed_reply(env, result));
     }
+    // The segments only borrow from `term`, so the rescheduled call walks
+    // it again instead of copying it to keep them around.
+    let job = dirty::blocking(move |env, term| {
+        let mut input = IoData::new(term)?;
+        input.select(range);
+        let result = run_compress(
+            &input,
+            block_size,
+            work_factor,
+            threads,
+            single_stream,
+            &control::Control::default(),
+        );
+        Ok(compressed_reply(env, result))
+    });
+    dirty::run(env, c"compress", term, job)
 }
 
-// i8 targets: Apple (all), x86_64-linux, FreeBSD (all), OpenBSD (all)
-#[cfg(any(
-    target_vendor = "apple",
-    all(target_arch = "x86_64", target_os = "linux"),
-    all(target_arch = "x86_64", target_os = "freebsd"),
-    all(target_arch = "aarch64", target_os = "freebsd"),
-    all(target_arch = "x86_64", target_os = "openbsd"),
-    all(target_arch = "aarch64", target_os = "openbsd"),
-))]
-#[rustler::nif(schedule = "DirtyCpu")]
+#[rustler::nif]
 fn decompress<'a>(
     env: Env<'a>,
     input: Binary<'a>,
-    small: bool,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let input_slice = input.as_slice();
-    let mut current_size = input_slice.len() * 4;
-    let mut output_vec = vec![0u8; current_size];
-
-    loop {
-        let mut dest_len = current_size as u32;
-
-        let result = unsafe {
-            libbz2_rs_sys::BZ2_bzBuffToBuffDecompress(
-                output_vec.as_mut_ptr() as *mut i8,
-                &mut dest_len,
-                input_slice.as_ptr() as *mut i8,
-                input_slice.len() as u32,
-                if small { 1 } else { 0 },
-                0,
+    options: DecompressOptions,
+    chunk_size: Option<usize>,
+) -> NifResult<Term<'a>> {
+    if chunk_size == Some(0) {
+        return Err(rustler::Error::BadArg);
+    }
+    let input = sub_binary(input, options.offset, options.length)?;
+    let DecompressOptions {
+        small,
+        multi_stream,
+        threads,
+        ..
+    } = options;
+    let (limits, expected) = (options.limits(), options.expected());
+    if threads > 1 && input.len() > SMALL_INPUT {
+        let job = dirty::blocking(move |env, input| {
+            let input = Binary::from_term(input)?;
+            let control = control::Control::default();
+            let Some(chunk_size) = chunk_size else {
+                let result = run_decompress(
+                    input.as_slice(),
+                    small,
+                    multi_stream,
+                    limits,
+                    expected,
+                    threads,
+                    &control,
+                );
+                return decompressed_reply(env, input, result);
+            };
+            let result = parallel::decompress_chunks(
+                input.as_slice(),
+                small,
+                multi_stream,
+                limits,
+                threads,
+                chunk_size,
+                &control,
             )
-        };
-
-        match result {
-            libbz2_rs_sys::BZ_OK => {
-                output_vec.truncate(dest_len as usize);
-                let mut binary = NewBinary::new(env, dest_len as usize);
-                binary.as_mut_slice().copy_from_slice(&output_vec);
-                return Ok((atoms::ok(), binary.into()));
-            }
-            libbz2_rs_sys::BZ_OUTBUFF_FULL => {
-                current_size *= 2;
-                if current_size > 1024 * 1024 * 1024 {
-                    let binary = NewBinary::new(env, 0);
-                    return Ok((atoms::outbuff_full(), binary.into()));
-                }
-                output_vec.resize(current_size, 0u8);
-            }
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into()));
+            .and_then(|(chunks, consumed)| {
+                expected.check(chunks.iter().map(Vec::len).sum())?;
+                Ok((chunks, consumed))
+            });
+            match result {
+                Ok((chunks, consumed)) => chunks_reply(env, input, chunks, consumed),
+                Err(code) => decompressed_reply(env, input, Err(code)),
             }
+        });
+        return dirty::run(env, c"decompress", input.to_term(env), job);
+    }
+
+    let mut decompression = match decoder::Decompression::new(
+        input.len(),
+        small,
+        multi_stream,
+        limits,
+        expected,
+        chunk_size,
+    ) {
+        Ok(decompression) => decompression,
+        Err(code) => return decompressed_reply(env, input, Err(code)),
+    };
+    // Small inputs with small blocks usually expand to little. Only those
+    // that do not are handed on to be finished like any other. A single step
+    // stops at the end of the first stream, so a later stream with larger
+    // blocks is never started here.
+    if input.len() <= SMALL_INPUT && small_blocks(input.as_slice()) {
+        let control = control::Control::default();
+        match decompression.step(input.as_slice(), SMALL_OUTPUT, &control) {
+            Ok(false) => {}
+            Ok(true) => return finished_reply(env, input, decompression, chunk_size),
+            Err(code) => return decompressed_reply(env, input, Err(code)),
         }
     }
+    let job = dirty::blocking(move |env, input| {
+        let input = Binary::from_term(input)?;
+        let control = control::Control::default();
+        loop {
+            match decompression.step(input.as_slice(), control::STEP, &control) {
+                Ok(false) => {}
+                Ok(true) => return finished_reply(env, input, decompression, chunk_size),
+                Err(code) => return decompressed_reply(env, input, Err(code)),
+            }
+        }
+    });
+    dirty::run(env, c"decompress", input.to_term(env), job)
+}
+
+#[derive(NifMap)]
+struct TestReport {
+    uncompressed_size: u64,
+    streams: usize,
+    blocks: usize,
 }
 
-// u8 target: aarch64-linux only
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
 #[rustler::nif(schedule = "DirtyCpu")]
-fn decompress<'a>(
-    env: Env<'a>,
-    input: Binary<'a>,
-    small: bool,
-) -> NifResult<(Atom, Binary<'a>)> {
+fn test(input: Binary, small: bool) -> Result<TestReport, Atom> {
     let input_slice = input.as_slice();
-    let mut current_size = input_slice.len() * 4;
-    let mut output_vec = vec![0u8; current_size];
+    let tested = decoder::test(input_slice, small).map_err(bz_error_to_atom)?;
+    let streams = scan::scan(&input_slice[..tested.consumed]).map_err(bz_error_to_atom)?;
+
+    Ok(TestReport {
+        uncompressed_size: tested.uncompressed_size,
+        streams: tested.streams,
+        blocks: streams.iter().map(|stream| stream.blocks.len()).sum(),
+    })
+}
 
-    loop {
-        let mut dest_len = current_size as u32;
-
-        let result = unsafe {
-            libbz2_rs_sys::BZ2_bzBuffToBuffDecompress(
-                output_vec.as_mut_ptr(),
-                &mut dest_len,
-                input_slice.as_ptr() as *mut u8,
-                input_slice.len() as u32,
-                if small { 1 } else { 0 },
-                0,
-            )
+// =============================================================================
+// Batch API
+// =============================================================================
+
+/// Encodes one result per batch element as `{:ok, binary}` or
+/// `{:error, reason}`.
+fn batch_reply<'a>(env: Env<'a>, results: Vec<Result<Vec<u8>, i32>>) -> Term<'a> {
+    let list: Vec<Term> = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(output) => (atoms::ok(), output_binary(env, output)).encode(env),
+            Err(code) => (atoms::error(), bz_error_to_atom(code)).encode(env),
+        })
+        .collect();
+    list.encode(env)
+}
+
+/// Compresses every binary in `inputs` with `block_size`, or else the
+/// smallest block size that fits each of them. With `threads` greater than
+/// one, the elements are spread over the worker pool, each compressed on a
+/// single thread.
+#[rustler::nif]
+fn compress_many<'a>(
+    env: Env<'a>,
+    inputs: Term<'a>,
+    block_size: Option<i32>,
+    work_factor: i32,
+    threads: usize,
+) -> NifResult<Term<'a>> {
+    let binaries = iodata::Binaries::new(inputs)?;
+    let size = binaries.size();
+    let job = dirty::blocking(move |env, _inputs| {
+        let control = control::Control::default();
+        let compress = |i: usize| {
+            let input = binaries.get(i);
+            let block_size =
+                block_size.unwrap_or_else(|| encoder::smallest_block_size(input.len()));
+            encoder::compress(input, block_size, work_factor, &control)
         };
+        let indices = (0..binaries.len()).collect::<Vec<_>>();
+        let results = if threads > 1 && binaries.len() > 1 && size > SMALL_INPUT {
+            pool::map(indices, threads, compress)
+        } else {
+            indices.into_iter().map(compress).collect()
+        };
+        Ok(batch_reply(env, results))
+    });
+    // As in `compress`, a batch this small is done right away.
+    if size <= SMALL_INPUT {
+        return job(env, inputs);
+    }
+    dirty::run(env, c"compress_many", inputs, job)
+}
 
-        match result {
-            libbz2_rs_sys::BZ_OK => {
-                output_vec.truncate(dest_len as usize);
-                let mut binary = NewBinary::new(env, dest_len as usize);
-                binary.as_mut_slice().copy_from_slice(&output_vec);
-                return Ok((atoms::ok(), binary.into()));
+/// Options of `decompress_many`, as validated by `Bz2Ex`.
+#[derive(NifMap, Clone, Copy)]
+struct DecompressManyOptions {
+    small: bool,
+    multi_stream: bool,
+    max_output_size: Option<u64>,
+    max_ratio: Option<f64>,
+    threads: usize,
+}
+
+/// Decodes a small batch right away, as `decompress` does with a small
+/// input, as long as every element has small blocks and all of them expand
+/// to no more than [`SMALL_OUTPUT`] together. `None` if it has to be handed
+/// on after all.
+fn decompress_small_batch(
+    binaries: &iodata::Binaries,
+    small: bool,
+    multi_stream: bool,
+    limits: decoder::Limits,
+) -> Option<Vec<Result<Vec<u8>, i32>>> {
+    let control = control::Control::default();
+    let mut budget = SMALL_OUTPUT;
+    (0..binaries.len())
+        .map(|i| {
+            let input = binaries.get(i);
+            if budget == 0 || !small_blocks(input) {
+                return None;
             }
-            libbz2_rs_sys::BZ_OUTBUFF_FULL => {
-                current_size *= 2;
-                if current_size > 1024 * 1024 * 1024 {
-                    let binary = NewBinary::new(env, 0);
-                    return Ok((atoms::outbuff_full(), binary.into()));
+            let mut decompression = match decoder::Decompression::new(
+                input.len(),
+                small,
+                multi_stream,
+                limits,
+                decoder::Expected::default(),
+                None,
+            ) {
+                Ok(decompression) => decompression,
+                Err(code) => return Some(Err(code)),
+            };
+            match decompression.step(input, budget, &control) {
+                Ok(false) => None,
+                Ok(true) => {
+                    let data = decompression.into_decoded().data;
+                    budget -= data.len();
+                    Some(Ok(data))
                 }
-                output_vec.resize(current_size, 0u8);
-            }
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into()));
+                Err(code) => Some(Err(code)),
             }
+        })
+        .collect()
+}
+
+/// Decompresses every binary in `inputs`, each on its own and with its own
+/// limits. With `threads` greater than one, the elements are spread over the
+/// worker pool, each decoded on a single thread.
+#[rustler::nif]
+fn decompress_many<'a>(
+    env: Env<'a>,
+    inputs: Term<'a>,
+    options: DecompressManyOptions,
+) -> NifResult<Term<'a>> {
+    let DecompressManyOptions {
+        small,
+        multi_stream,
+        max_output_size,
+        max_ratio,
+        threads,
+    } = options;
+    let binaries = iodata::Binaries::new(inputs)?;
+    let limits = decoder::Limits {
+        max_output_size,
+        max_ratio,
+    };
+    let size = binaries.size();
+    if size <= SMALL_INPUT {
+        if let Some(results) = decompress_small_batch(&binaries, small, multi_stream, limits) {
+            return Ok(batch_reply(env, results));
         }
     }
+    let job = dirty::blocking(move |env, _inputs| {
+        let control = control::Control::default();
+        let decompress = |i: usize| {
+            decoder::decompress(
+                binaries.get(i),
+                small,
+                multi_stream,
+                limits,
+                decoder::Expected::default(),
+                &control,
+            )
+            .map(|decoded| decoded.data)
+        };
+        let indices = (0..binaries.len()).collect::<Vec<_>>();
+        let results = if threads > 1 && binaries.len() > 1 && size > SMALL_INPUT {
+            pool::map(indices, threads, decompress)
+        } else {
+            indices.into_iter().map(decompress).collect()
+        };
+        Ok(batch_reply(env, results))
+    });
+    dirty::run(env, c"decompress_many", inputs, job)
 }
 
 // =============================================================================
-// Streaming API - Resources
+// Cancellation
 // =============================================================================
 
-struct CompressStreamInner {
-    stream: Box<libbz2_rs_sys::bz_stream>,
-    initialized: bool,
-}
-
-unsafe impl Send for CompressStreamInner {}
-unsafe impl Sync for CompressStreamInner {}
-
-pub struct CompressStream {
-    inner: Mutex<CompressStreamInner>,
+/// Shared flag that async jobs and streams check between steps.
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
 }
 
 #[rustler::resource_impl]
-impl rustler::Resource for CompressStream {}
+impl rustler::Resource for CancelHandle {}
 
-impl CompressStream {
-    fn new(block_size: i32, work_factor: i32) -> Result<Self, i32> {
-        let mut stream = Box::new(libbz2_rs_sys::bz_stream {
-            next_in: std::ptr::null_mut(),
-            avail_in: 0,
-            total_in_lo32: 0,
-            total_in_hi32: 0,
-            next_out: std::ptr::null_mut(),
-            avail_out: 0,
-            total_out_lo32: 0,
-            total_out_hi32: 0,
-            state: std::ptr::null_mut(),
-            bzalloc: None,
-            bzfree: None,
-            opaque: std::ptr::null_mut(),
-        });
+fn cancel_flag(cancel: Option<ResourceArc<CancelHandle>>) -> Option<Arc<AtomicBool>> {
+    cancel.map(|handle| Arc::clone(&handle.cancelled))
+}
 
-        let result = unsafe {
-            libbz2_rs_sys::BZ2_bzCompressInit(&mut *stream, block_size, 0, work_factor)
-        };
+fn control(cancel: Option<ResourceArc<CancelHandle>>) -> control::Control {
+    control::Control::new(cancel_flag(cancel), None)
+}
 
-        if result == libbz2_rs_sys::BZ_OK {
-            Ok(Self {
-                inner: Mutex::new(CompressStreamInner {
-                    stream,
-                    initialized: true,
-                }),
-            })
-        } else {
-            Err(result)
-        }
-    }
+#[rustler::nif]
+fn cancel_handle_new() -> ResourceArc<CancelHandle> {
+    ResourceArc::new(CancelHandle {
+        cancelled: Arc::new(AtomicBool::new(false)),
+    })
 }
 
-impl Drop for CompressStream {
-    fn drop(&mut self) {
-        let mut inner = self.inner.lock().unwrap();
-        if inner.initialized {
-            unsafe {
-                libbz2_rs_sys::BZ2_bzCompressEnd(&mut *inner.stream);
-            }
-            inner.initialized = false;
-        }
-    }
+#[rustler::nif]
+fn cancel_handle_cancel(handle: ResourceArc<CancelHandle>) -> Atom {
+    handle.cancelled.store(true, Ordering::Relaxed);
+    atoms::ok()
 }
 
-struct DecompressStreamInner {
-    stream: Box<libbz2_rs_sys::bz_stream>,
-    initialized: bool,
+#[rustler::nif]
+fn cancel_handle_cancelled(handle: ResourceArc<CancelHandle>) -> bool {
+    handle.cancelled.load(Ordering::Relaxed)
 }
 
-unsafe impl Send for DecompressStreamInner {}
-unsafe impl Sync for DecompressStreamInner {}
+// =============================================================================
+// Async API
+// =============================================================================
 
-pub struct DecompressStream {
-    inner: Mutex<DecompressStreamInner>,
+/// Sends `{:bz2_progress, reference, bytes_in, bytes_out}` to `pid` every
+/// `interval` bytes of input.
+fn progress_reporter(pid: LocalPid, interval: u64, reference: Reference) -> control::Progress {
+    let owned = OwnedEnv::new();
+    let saved_reference = owned.save(reference);
+    let holder = Mutex::new((owned, saved_reference));
+
+    control::Progress::new(interval.max(1), move |bytes_in, bytes_out| {
+        let holder = holder.lock().unwrap();
+        let (owned, saved_reference) = &*holder;
+        owned.run(|holder_env| {
+            let reference = saved_reference.load(holder_env);
+            // A fresh environment per message keeps `owned` from growing.
+            let _ = OwnedEnv::new().send_and_clear(&pid, |env| {
+                (
+                    atoms::bz2_progress(),
+                    reference.in_env(env),
+                    bytes_in,
+                    bytes_out,
+                )
+            });
+        });
+    })
 }
 
-#[rustler::resource_impl]
-impl rustler::Resource for DecompressStream {}
-
-impl DecompressStream {
-    fn new(small: bool) -> Result<Self, i32> {
-        let mut stream = Box::new(libbz2_rs_sys::bz_stream {
-            next_in: std::ptr::null_mut(),
-            avail_in: 0,
-            total_in_lo32: 0,
-            total_in_hi32: 0,
-            next_out: std::ptr::null_mut(),
-            avail_out: 0,
-            total_out_lo32: 0,
-            total_out_hi32: 0,
-            state: std::ptr::null_mut(),
-            bzalloc: None,
-            bzfree: None,
-            opaque: std::ptr::null_mut(),
+/// Runs `work` over `input` on the worker pool and returns a reference right
+/// away. When the work is done, `{reference, {:ok, binary}}` or
+/// `{reference, {:error, reason}}` is sent to the calling process.
+///
+/// `progress`, if given, is the pid and byte interval for progress messages.
+fn spawn_job<'a, F>(
+    env: Env<'a>,
+    input: Binary<'a>,
+    cancel: Option<ResourceArc<CancelHandle>>,
+    progress: Option<(LocalPid, u64)>,
+    work: F,
+) -> Reference<'a>
+where
+    F: FnOnce(&[u8], &control::Control) -> Result<Vec<u8>, i32> + Send + 'static,
+{
+    let pid = env.pid();
+    let reference = env.make_ref();
+    let progress =
+        progress.map(|(pid, interval)| Arc::new(progress_reporter(pid, interval, reference)));
+    let control = control::Control::new(cancel_flag(cancel), progress);
+    let mut owned = OwnedEnv::new();
+    // Keeps the input binary alive, without copying it, until the job is done.
+    let saved_input = owned.save(input);
+    let saved_reference = owned.save(reference);
+
+    pool::execute(move || {
+        // `None` if the work panicked.
+        let result = owned.run(|env| {
+            let input: Binary = saved_input.load(env).decode().ok()?;
+            panic::catch_unwind(AssertUnwindSafe(|| work(input.as_slice(), &control))).ok()
+        });
+        let _ = owned.send_and_clear(&pid, |env| {
+            let reply = match result {
+                Some(Ok(output)) => (atoms::ok(), output_binary(env, output)).encode(env),
+                Some(Err(code)) => (atoms::error(), bz_error_to_atom(code)).encode(env),
+                None => (atoms::error(), atoms::unknown_error()).encode(env),
+            };
+            (saved_reference.load(env), reply)
         });
+    });
 
-        let result = unsafe {
-            libbz2_rs_sys::BZ2_bzDecompressInit(&mut *stream, 0, if small { 1 } else { 0 })
-        };
+    reference
+}
 
-        if result == libbz2_rs_sys::BZ_OK {
-            Ok(Self {
-                inner: Mutex::new(DecompressStreamInner {
-                    stream,
-                    initialized: true,
-                }),
-            })
-        } else {
-            Err(result)
-        }
-    }
+#[rustler::nif]
+fn compress_async<'a>(
+    env: Env<'a>,
+    input: Binary<'a>,
+    options: CompressOptions,
+    cancel: Option<ResourceArc<CancelHandle>>,
+    progress: Option<(LocalPid, u64)>,
+) -> NifResult<Reference<'a>> {
+    let input = sub_binary(input, options.offset, options.length)?;
+    let block_size = options
+        .block_size
+        .unwrap_or_else(|| encoder::smallest_block_size(input.len()));
+    Ok(spawn_job(
+        env,
+        input,
+        cancel,
+        progress,
+        move |input, control| {
+            run_compress(
+                input,
+                block_size,
+                options.work_factor,
+                options.threads,
+                options.single_stream,
+                control,
+            )
+        },
+    ))
 }
 
-impl Drop for DecompressStream {
-    fn drop(&mut self) {
-        let mut inner = self.inner.lock().unwrap();
-        if inner.initialized {
-            unsafe {
-                libbz2_rs_sys::BZ2_bzDecompressEnd(&mut *inner.stream);
-            }
-            inner.initialized = false;
-        }
-    }
+#[rustler::nif]
+fn decompress_async<'a>(
+    env: Env<'a>,
+    input: Binary<'a>,
+    options: DecompressOptions,
+    cancel: Option<ResourceArc<CancelHandle>>,
+    progress: Option<(LocalPid, u64)>,
+) -> NifResult<Reference<'a>> {
+    let input = sub_binary(input, options.offset, options.length)?;
+    Ok(spawn_job(
+        env,
+        input,
+        cancel,
+        progress,
+        move |input, control| {
+            run_decompress(
+                input,
+                options.small,
+                options.multi_stream,
+                options.limits(),
+                options.expected(),
+                options.threads,
+                control,
+            )
+            .map(|decoded| decoded.data)
+        },
+    ))
 }
 
 // =============================================================================
-// Streaming NIFs
+// Inspection
 // =============================================================================
 
-#[rustler::nif]
-fn compress_stream_init(
-    block_size: i32,
-    work_factor: i32,
-) -> NifResult<(Atom, ResourceArc<CompressStream>)> {
-    match CompressStream::new(block_size, work_factor) {
-        Ok(stream) => Ok((atoms::ok(), ResourceArc::new(stream))),
-        Err(code) => Err(rustler::Error::Term(Box::new(bz_error_to_atom(code)))),
-    }
+#[derive(NifMap)]
+struct BlockInfo {
+    bit_offset: u64,
+    bit_length: u64,
+    crc: u32,
+}
+
+#[derive(NifMap)]
+struct StreamInfo {
+    offset: usize,
+    length: usize,
+    block_size: u8,
+    crc: u32,
+    blocks: Vec<BlockInfo>,
+}
+
+#[derive(NifMap)]
+struct Info {
+    block_size: u8,
+    streams: Vec<StreamInfo>,
 }
 
-// i8 targets: Apple (all), x86_64-linux, FreeBSD (all), OpenBSD (all)
-#[cfg(any(
-    target_vendor = "apple",
-    all(target_arch = "x86_64", target_os = "linux"),
-    all(target_arch = "x86_64", target_os = "freebsd"),
-    all(target_arch = "aarch64", target_os = "freebsd"),
-    all(target_arch = "x86_64", target_os = "openbsd"),
-    all(target_arch = "aarch64", target_os = "openbsd"),
-))]
 #[rustler::nif(schedule = "DirtyCpu")]
-fn compress_stream_deflate<'a>(
-    env: Env<'a>,
-    stream: ResourceArc<CompressStream>,
-    input: Binary<'a>,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
-    }
+fn info(input: Binary) -> Result<Info, Atom> {
+    let streams = scan::scan(input.as_slice()).map_err(bz_error_to_atom)?;
+
+    Ok(Info {
+        block_size: streams[0].level,
+        streams: streams
+            .into_iter()
+            .map(|stream| StreamInfo {
+                offset: stream.offset,
+                length: stream.length,
+                block_size: stream.level,
+                crc: stream.crc,
+                blocks: stream
+                    .blocks
+                    .into_iter()
+                    .map(|block| BlockInfo {
+                        bit_offset: block.bit_offset,
+                        bit_length: block.bit_length,
+                        crc: block.crc,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    })
+}
 
-    let input_slice = input.as_slice();
-    let mut output_vec = vec![0u8; input_slice.len() + 600];
+// =============================================================================
+// Random access
+// =============================================================================
 
-    inner.stream.next_in = input_slice.as_ptr() as *const i8;
-    inner.stream.avail_in = input_slice.len() as u32;
-    inner.stream.next_out = output_vec.as_mut_ptr() as *mut i8;
-    inner.stream.avail_out = output_vec.len() as u32;
+pub struct BlockIndex {
+    index: index::Index,
+}
 
-    let result = unsafe { libbz2_rs_sys::BZ2_bzCompress(&mut *inner.stream, libbz2_rs_sys::BZ_RUN) };
+#[rustler::resource_impl]
+impl rustler::Resource for BlockIndex {}
+
+#[derive(NifMap)]
+struct IndexBlockInfo {
+    bit_offset: u64,
+    bit_length: u64,
+    uncompressed_offset: u64,
+    uncompressed_size: u64,
+    crc: u32,
+}
 
-    if result == libbz2_rs_sys::BZ_RUN_OK {
-        let bytes_written = output_vec.len() - inner.stream.avail_out as usize;
-        output_vec.truncate(bytes_written);
+#[derive(NifMap)]
+struct IndexInfo {
+    block_size: u8,
+    uncompressed_size: u64,
+    blocks: Vec<IndexBlockInfo>,
+}
 
-        let mut binary = NewBinary::new(env, bytes_written);
-        binary.as_mut_slice().copy_from_slice(&output_vec);
-        Ok((atoms::ok(), binary.into()))
-    } else {
-        let binary = NewBinary::new(env, 0);
-        Ok((bz_error_to_atom(result), binary.into()))
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_build(input: Binary, small: bool) -> Result<ResourceArc<BlockIndex>, Atom> {
+    match index::build(input.as_slice(), small) {
+        Ok(index) => Ok(ResourceArc::new(BlockIndex { index })),
+        Err(code) => Err(bz_error_to_atom(code)),
     }
 }
 
-// u8 target: aarch64-linux only
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
 #[rustler::nif(schedule = "DirtyCpu")]
-fn compress_stream_deflate<'a>(
+fn index_read<'a>(
     env: Env<'a>,
-    stream: ResourceArc<CompressStream>,
+    index: ResourceArc<BlockIndex>,
     input: Binary<'a>,
+    offset: u64,
+    length: u64,
+    small: bool,
 ) -> NifResult<(Atom, Binary<'a>)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
+    match index::read(input.as_slice(), &index.index, offset, length, small) {
+        Ok(output) => Ok((atoms::ok(), output_binary(env, output))),
+        Err(code) => {
+            let binary = NewBinary::new(env, 0);
+            Ok((bz_error_to_atom(code), binary.into()))
+        }
     }
+}
 
-    let input_slice = input.as_slice();
-    let mut output_vec = vec![0u8; input_slice.len() + 600];
+#[rustler::nif]
+fn index_encode(env: Env, index: ResourceArc<BlockIndex>) -> Binary {
+    output_binary(env, index.index.encode())
+}
 
-    inner.stream.next_in = input_slice.as_ptr();
-    inner.stream.avail_in = input_slice.len() as u32;
-    inner.stream.next_out = output_vec.as_mut_ptr();
-    inner.stream.avail_out = output_vec.len() as u32;
+#[rustler::nif]
+fn index_decode(input: Binary) -> Result<ResourceArc<BlockIndex>, Atom> {
+    match index::Index::decode(input.as_slice()) {
+        Ok(index) => Ok(ResourceArc::new(BlockIndex { index })),
+        Err(index::FormatError::Invalid) => Err(atoms::invalid_index()),
+        Err(index::FormatError::UnsupportedVersion) => Err(atoms::unsupported_version()),
+    }
+}
+
+#[rustler::nif]
+fn index_info(index: ResourceArc<BlockIndex>) -> IndexInfo {
+    let index = &index.index;
+
+    IndexInfo {
+        block_size: index.level,
+        uncompressed_size: index.uncompressed_size,
+        blocks: index
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| IndexBlockInfo {
+                bit_offset: entry.bit_offset,
+                bit_length: entry.bit_length,
+                uncompressed_offset: entry.uncompressed_offset,
+                uncompressed_size: index.block_size(i),
+                crc: entry.crc,
+            })
+            .collect(),
+    }
+}
 
-    let result = unsafe { libbz2_rs_sys::BZ2_bzCompress(&mut *inner.stream, libbz2_rs_sys::BZ_RUN) };
+// =============================================================================
+// Streaming API - Resources
+// =============================================================================
 
-    if result == libbz2_rs_sys::BZ_RUN_OK {
-        let bytes_written = output_vec.len() - inner.stream.avail_out as usize;
-        output_vec.truncate(bytes_written);
+struct CompressStreamInner {
+    /// `None` once the stream has been finished or cancelled.
+    encoder: Option<encoder::Encoder>,
+    control: control::Control,
+}
 
-        let mut binary = NewBinary::new(env, bytes_written);
-        binary.as_mut_slice().copy_from_slice(&output_vec);
-        Ok((atoms::ok(), binary.into()))
-    } else {
-        let binary = NewBinary::new(env, 0);
-        Ok((bz_error_to_atom(result), binary.into()))
-    }
+pub struct CompressStream {
+    inner: Mutex<CompressStreamInner>,
 }
 
-// i8 targets: Apple (all), x86_64-linux, FreeBSD (all), OpenBSD (all)
-#[cfg(any(
-    target_vendor = "apple",
-    all(target_arch = "x86_64", target_os = "linux"),
-    all(target_arch = "x86_64", target_os = "freebsd"),
-    all(target_arch = "aarch64", target_os = "freebsd"),
-    all(target_arch = "x86_64", target_os = "openbsd"),
-    all(target_arch = "aarch64", target_os = "openbsd"),
-))]
-#[rustler::nif(schedule = "DirtyCpu")]
-fn compress_stream_finish<'a>(
-    env: Env<'a>,
-    stream: ResourceArc<CompressStream>,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
-    }
+#[rustler::resource_impl]
+impl rustler::Resource for CompressStream {}
 
-    let mut output_chunks: Vec<u8> = Vec::new();
-    let mut buffer = vec![0u8; 4096];
+impl CompressStream {
+    fn new(block_size: i32, work_factor: i32, control: control::Control) -> Result<Self, i32> {
+        let encoder = encoder::Encoder::new(block_size, work_factor)?;
+        Ok(Self {
+            inner: Mutex::new(CompressStreamInner {
+                encoder: Some(encoder),
+                control,
+            }),
+        })
+    }
+}
 
-    loop {
-        inner.stream.next_in = std::ptr::null();
-        inner.stream.avail_in = 0;
-        inner.stream.next_out = buffer.as_mut_ptr() as *mut i8;
-        inner.stream.avail_out = buffer.len() as u32;
+struct ParallelCompressStreamInner {
+    /// `None` once the stream has been finished or has failed.
+    encoder: Option<pipeline::Encoder>,
+}
 
-        let result =
-            unsafe { libbz2_rs_sys::BZ2_bzCompress(&mut *inner.stream, libbz2_rs_sys::BZ_FINISH) };
+pub struct ParallelCompressStream {
+    inner: Mutex<ParallelCompressStreamInner>,
+}
 
-        let bytes_written = buffer.len() - inner.stream.avail_out as usize;
-        output_chunks.extend_from_slice(&buffer[..bytes_written]);
+#[rustler::resource_impl]
+impl rustler::Resource for ParallelCompressStream {}
+
+impl ParallelCompressStream {
+    fn new(
+        block_size: i32,
+        work_factor: i32,
+        threads: usize,
+        control: control::Control,
+    ) -> Result<Self, i32> {
+        let encoder = pipeline::Encoder::new(block_size, work_factor, threads, control)?;
+        Ok(Self {
+            inner: Mutex::new(ParallelCompressStreamInner {
+                encoder: Some(encoder),
+            }),
+        })
+    }
+}
 
-        match result {
-            libbz2_rs_sys::BZ_STREAM_END => {
-                unsafe {
-                    libbz2_rs_sys::BZ2_bzCompressEnd(&mut *inner.stream);
-                }
-                inner.initialized = false;
+/// Either kind of compression stream, so that both share the deflate and
+/// finish NIFs.
+enum AnyCompressStream {
+    Serial(ResourceArc<CompressStream>),
+    Parallel(ResourceArc<ParallelCompressStream>),
+}
 
-                let mut binary = NewBinary::new(env, output_chunks.len());
-                binary.as_mut_slice().copy_from_slice(&output_chunks);
-                return Ok((atoms::ok(), binary.into()));
-            }
-            libbz2_rs_sys::BZ_FINISH_OK => {}
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into()));
-            }
+impl<'a> rustler::Decoder<'a> for AnyCompressStream {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        match term.decode() {
+            Ok(stream) => Ok(Self::Serial(stream)),
+            Err(_) => term.decode().map(Self::Parallel),
         }
     }
 }
 
-// u8 target: aarch64-linux only
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-#[rustler::nif(schedule = "DirtyCpu")]
-fn compress_stream_finish<'a>(
-    env: Env<'a>,
-    stream: ResourceArc<CompressStream>,
-) -> NifResult<(Atom, Binary<'a>)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
-    }
+struct DecompressStreamInner {
+    /// `None` once the end of the stream has been reached, a limit was hit or
+    /// the stream was cancelled.
+    decoder: Option<decoder::Decoder>,
+    limits: decoder::Limits,
+    control: control::Control,
+}
 
-    let mut output_chunks: Vec<u8> = Vec::new();
-    let mut buffer = vec![0u8; 4096];
+pub struct DecompressStream {
+    inner: Mutex<DecompressStreamInner>,
+}
 
-    loop {
-        inner.stream.next_in = std::ptr::null();
-        inner.stream.avail_in = 0;
-        inner.stream.next_out = buffer.as_mut_ptr();
-        inner.stream.avail_out = buffer.len() as u32;
+#[rustler::resource_impl]
+impl rustler::Resource for DecompressStream {}
 
-        let result =
-            unsafe { libbz2_rs_sys::BZ2_bzCompress(&mut *inner.stream, libbz2_rs_sys::BZ_FINISH) };
+impl DecompressStream {
+    fn new(small: bool, limits: decoder::Limits, control: control::Control) -> Result<Self, i32> {
+        let decoder = decoder::Decoder::new(small)?;
+        Ok(Self {
+            inner: Mutex::new(DecompressStreamInner {
+                decoder: Some(decoder),
+                limits,
+                control,
+            }),
+        })
+    }
+}
 
-        let bytes_written = buffer.len() - inner.stream.avail_out as usize;
-        output_chunks.extend_from_slice(&buffer[..bytes_written]);
+// =============================================================================
+// Streaming NIFs
+// =============================================================================
 
-        match result {
-            libbz2_rs_sys::BZ_STREAM_END => {
-                unsafe {
-                    libbz2_rs_sys::BZ2_bzCompressEnd(&mut *inner.stream);
-                }
-                inner.initialized = false;
+#[rustler::nif]
+fn compress_stream_init(
+    block_size: i32,
+    work_factor: i32,
+    cancel: Option<ResourceArc<CancelHandle>>,
+) -> NifResult<(Atom, ResourceArc<CompressStream>)> {
+    match CompressStream::new(block_size, work_factor, control(cancel)) {
+        Ok(stream) => Ok((atoms::ok(), ResourceArc::new(stream))),
+        Err(code) => Err(rustler::Error::Term(Box::new(bz_error_to_atom(code)))),
+    }
+}
 
-                let mut binary = NewBinary::new(env, output_chunks.len());
-                binary.as_mut_slice().copy_from_slice(&output_chunks);
-                return Ok((atoms::ok(), binary.into()));
+#[rustler::nif]
+fn parallel_compress_stream_init(
+    block_size: i32,
+    work_factor: i32,
+    threads: usize,
+    cancel: Option<ResourceArc<CancelHandle>>,
+) -> NifResult<(Atom, ResourceArc<ParallelCompressStream>)> {
+    match ParallelCompressStream::new(block_size, work_factor, threads, control(cancel)) {
+        Ok(stream) => Ok((atoms::ok(), ResourceArc::new(stream))),
+        Err(code) => Err(rustler::Error::Term(Box::new(bz_error_to_atom(code)))),
+    }
+}
+
+fn sequence_error() -> rustler::Error {
+    rustler::Error::Term(Box::new(atoms::sequence_error()))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn compress_stream_deflate<'a>(
+    env: Env<'a>,
+    stream: AnyCompressStream,
+    term: Term<'a>,
+) -> NifResult<Term<'a>> {
+    let input = IoData::new(term)?;
+    // The stream stays locked for the whole call, so that concurrent calls
+    // cannot interleave their input.
+    let result = match stream {
+        AnyCompressStream::Serial(stream) => {
+            let mut guard = stream.inner.lock().unwrap();
+            let inner = &mut *guard;
+            let Some(encoder) = inner.encoder.as_mut() else {
+                return Err(sequence_error());
+            };
+            let mut output = Vec::new();
+            let result = input
+                .segments()
+                .try_for_each(|segment| encoder::run(encoder, segment, &mut output, &inner.control))
+                .map(|()| output);
+            if result == Err(control::CANCELLED) {
+                inner.encoder = None;
             }
-            libbz2_rs_sys::BZ_FINISH_OK => {}
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into()));
+            result
+        }
+        AnyCompressStream::Parallel(stream) => {
+            let mut inner = stream.inner.lock().unwrap();
+            let Some(encoder) = inner.encoder.as_mut() else {
+                return Err(sequence_error());
+            };
+            let mut output = Vec::new();
+            let result = input
+                .segments()
+                .try_for_each(|segment| {
+                    output.extend(encoder.write(segment)?);
+                    Ok(())
+                })
+                .map(|()| output);
+            if result.is_err() {
+                inner.encoder = None;
             }
+            result
         }
-    }
+    };
+    Ok(compressed_reply(env, result))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn compress_stream_finish<'a>(env: Env<'a>, stream: AnyCompressStream) -> NifResult<Term<'a>> {
+    let result = match stream {
+        AnyCompressStream::Serial(stream) => {
+            let mut guard = stream.inner.lock().unwrap();
+            let inner = &mut *guard;
+            let Some(encoder) = inner.encoder.as_mut() else {
+                return Err(sequence_error());
+            };
+            let mut output = Vec::new();
+            let result = encoder::finish(encoder, &mut output, &inner.control).map(|()| output);
+            if matches!(result, Ok(_) | Err(control::CANCELLED)) {
+                inner.encoder = None;
+            }
+            result
+        }
+        AnyCompressStream::Parallel(stream) => {
+            let mut inner = stream.inner.lock().unwrap();
+            let Some(encoder) = inner.encoder.take() else {
+                return Err(sequence_error());
+            };
+            encoder.finish()
+        }
+    };
+    Ok(compressed_reply(env, result))
 }
 
 #[rustler::nif]
-fn decompress_stream_init(small: bool) -> NifResult<(Atom, ResourceArc<DecompressStream>)> {
-    match DecompressStream::new(small) {
+fn decompress_stream_init(
+    small: bool,
+    max_output_size: Option<u64>,
+    max_ratio: Option<f64>,
+    cancel: Option<ResourceArc<CancelHandle>>,
+) -> NifResult<(Atom, ResourceArc<DecompressStream>)> {
+    let limits = decoder::Limits {
+        max_output_size,
+        max_ratio,
+    };
+    match DecompressStream::new(small, limits, control(cancel)) {
         Ok(stream) => Ok((atoms::ok(), ResourceArc::new(stream))),
         Err(code) => Err(rustler::Error::Term(Box::new(bz_error_to_atom(code)))),
     }
 }
 
-// i8 targets: Apple (all), x86_64-linux, FreeBSD (all), OpenBSD (all)
-#[cfg(any(
-    target_vendor = "apple",
-    all(target_arch = "x86_64", target_os = "linux"),
-    all(target_arch = "x86_64", target_os = "freebsd"),
-    all(target_arch = "aarch64", target_os = "freebsd"),
-    all(target_arch = "x86_64", target_os = "openbsd"),
-    all(target_arch = "aarch64", target_os = "openbsd"),
-))]
+fn inflate_error(env: Env, reason: Atom) -> Term {
+    let binary = NewBinary::new(env, 0);
+    let rest = NewBinary::new(env, 0);
+    (
+        reason,
+        Binary::from(binary),
+        atoms::error(),
+        Binary::from(rest),
+    )
+        .encode(env)
+}
+
 #[rustler::nif(schedule = "DirtyCpu")]
 fn decompress_stream_inflate<'a>(
     env: Env<'a>,
     stream: ResourceArc<DecompressStream>,
     input: Binary<'a>,
-) -> NifResult<(Atom, Binary<'a>, Atom)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
-    }
+) -> NifResult<Term<'a>> {
+    // Held for the whole call, as in `compress_stream_deflate`.
+    let mut guard = stream.inner.lock().unwrap();
+    let inner = &mut *guard;
+    let limits = inner.limits;
+    let Some(decoder) = inner.decoder.as_mut() else {
+        return Err(sequence_error());
+    };
 
     let input_slice = input.as_slice();
-    let mut output_chunks: Vec<u8> = Vec::new();
-    let mut buffer = vec![0u8; input_slice.len() * 4 + 4096];
-
-    inner.stream.next_in = input_slice.as_ptr() as *const i8;
-    inner.stream.avail_in = input_slice.len() as u32;
+    let mut output = vec![0u8; input_slice.len().saturating_mul(4).max(4096)];
+    let mut filled = 0;
+    let mut pos = 0;
 
     loop {
-        inner.stream.next_out = buffer.as_mut_ptr() as *mut i8;
-        inner.stream.avail_out = buffer.len() as u32;
+        if let Err(code) = inner.control.check() {
+            inner.decoder = None;
+            return Ok(inflate_error(env, bz_error_to_atom(code)));
+        }
+        if filled == output.len() {
+            output.resize(output.len() * 2, 0u8);
+        }
 
-        let result = unsafe { libbz2_rs_sys::BZ2_bzDecompress(&mut *inner.stream) };
+        let end = output.len().min(filled + control::STEP);
+        let step = decoder.step(&input_slice[pos..], &mut output[filled..end]);
+        pos += step.consumed;
+        filled += step.produced;
 
-        let bytes_written = buffer.len() - inner.stream.avail_out as usize;
-        output_chunks.extend_from_slice(&buffer[..bytes_written]);
+        let (total_in, total_out) = decoder.totals();
+        if total_out > limits.max_output(total_in) {
+            inner.decoder = None;
+            return Ok(inflate_error(env, atoms::output_limit_exceeded()));
+        }
 
-        match result {
+        match step.code {
             libbz2_rs_sys::BZ_OK => {
-                if inner.stream.avail_in == 0 {
-                    let mut binary = NewBinary::new(env, output_chunks.len());
-                    binary.as_mut_slice().copy_from_slice(&output_chunks);
-                    return Ok((atoms::ok(), binary.into(), atoms::ready()));
+                if pos < input_slice.len() || step.output_full {
+                    continue;
                 }
+                output.truncate(filled);
+                let rest = NewBinary::new(env, 0);
+                return Ok((
+                    atoms::ok(),
+                    output_binary(env, output),
+                    atoms::ready(),
+                    Binary::from(rest),
+                )
+                    .encode(env));
             }
             libbz2_rs_sys::BZ_STREAM_END => {
-                unsafe {
-                    libbz2_rs_sys::BZ2_bzDecompressEnd(&mut *inner.stream);
-                }
-                inner.initialized = false;
-
-                let mut binary = NewBinary::new(env, output_chunks.len());
-                binary.as_mut_slice().copy_from_slice(&output_chunks);
-                return Ok((atoms::ok(), binary.into(), atoms::finished()));
-            }
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into(), atoms::error()));
+                inner.decoder = None;
+
+                output.truncate(filled);
+                let rest = input.make_subbinary(pos, input_slice.len() - pos)?;
+                return Ok((
+                    atoms::ok(),
+                    output_binary(env, output),
+                    atoms::finished(),
+                    rest,
+                )
+                    .encode(env));
             }
+            code => return Ok(inflate_error(env, bz_error_to_atom(code))),
         }
     }
 }
 
-// u8 target: aarch64-linux only
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-#[rustler::nif(schedule = "DirtyCpu")]
-fn decompress_stream_inflate<'a>(
-    env: Env<'a>,
-    stream: ResourceArc<DecompressStream>,
-    input: Binary<'a>,
-) -> NifResult<(Atom, Binary<'a>, Atom)> {
-    let mut inner = stream.inner.lock().unwrap();
-    if !inner.initialized {
-        return Err(rustler::Error::Term(Box::new(atoms::sequence_error())));
-    }
-
-    let input_slice = input.as_slice();
-    let mut output_chunks: Vec<u8> = Vec::new();
-    let mut buffer = vec![0u8; input_slice.len() * 4 + 4096];
-
-    inner.stream.next_in = input_slice.as_ptr();
-    inner.stream.avail_in = input_slice.len() as u32;
-
-    loop {
-        inner.stream.next_out = buffer.as_mut_ptr();
-        inner.stream.avail_out = buffer.len() as u32;
+// =============================================================================
+// Worker pool
+// =============================================================================
 
-        let result = unsafe { libbz2_rs_sys::BZ2_bzDecompress(&mut *inner.stream) };
+#[derive(NifMap)]
+struct PoolStats {
+    size: usize,
+    workers: usize,
+    active: usize,
+    queued: usize,
+}
 
-        let bytes_written = buffer.len() - inner.stream.avail_out as usize;
-        output_chunks.extend_from_slice(&buffer[..bytes_written]);
+#[rustler::nif]
+fn pool_size() -> usize {
+    pool::size()
+}
 
-        match result {
-            libbz2_rs_sys::BZ_OK => {
-                if inner.stream.avail_in == 0 {
-                    let mut binary = NewBinary::new(env, output_chunks.len());
-                    binary.as_mut_slice().copy_from_slice(&output_chunks);
-                    return Ok((atoms::ok(), binary.into(), atoms::ready()));
-                }
-            }
-            libbz2_rs_sys::BZ_STREAM_END => {
-                unsafe {
-                    libbz2_rs_sys::BZ2_bzDecompressEnd(&mut *inner.stream);
-                }
-                inner.initialized = false;
+#[rustler::nif]
+fn pool_set_size(size: usize) -> NifResult<Atom> {
+    if size == 0 {
+        return Err(rustler::Error::BadArg);
+    }
+    pool::set_size(size);
+    Ok(atoms::ok())
+}
 
-                let mut binary = NewBinary::new(env, output_chunks.len());
-                binary.as_mut_slice().copy_from_slice(&output_chunks);
-                return Ok((atoms::ok(), binary.into(), atoms::finished()));
-            }
-            _ => {
-                let binary = NewBinary::new(env, 0);
-                return Ok((bz_error_to_atom(result), binary.into(), atoms::error()));
-            }
-        }
+#[rustler::nif]
+fn pool_stats() -> PoolStats {
+    let stats = pool::stats();
+    PoolStats {
+        size: stats.size,
+        workers: stats.workers,
+        active: stats.active,
+        queued: stats.queued,
     }
 }
 
@@ -677,4 +1246,13 @@ fn decompress_stream_inflate<'a>(
 // NIF Registration
 // =============================================================================
 
-rustler::init!("Elixir.Bz2Ex.Native");
\ No newline at end of file
+/// Applies the pool size passed as load data, if any. No threads are started
+/// until the pool is first used.
+fn load(_env: Env, load_info: Term) -> bool {
+    if let Ok(size @ 1..) = load_info.decode::<usize>() {
+        pool::set_size(size);
+    }
+    true
+}
+
+rustler::init!("Elixir.Bz2Ex.Native", load = load);
@@ -0,0 +1,489 @@
+//! Multi-threaded compression and decompression on the worker
+//! [`pool`](crate::pool).
+//!
+//! Compression cuts the input exactly where the sequential encoder would end
+//! each block, so that every chunk fills one block. By default each chunk
+//! then becomes a stream of its own, as with pbzip2. Concatenated streams are
+//! valid bzip2 data, and every decoder that handles `cat a.bz2 b.bz2` reads the
+//! result back in one piece. Some readers stop after the first stream, so
+//! there is also a single-stream mode in the style of lbzip2: the chunks are
+//! compressed independently, and their blocks are then spliced bit by bit
+//! behind one stream header. Since blocks do not depend on each other, the
+//! result is byte for byte what [`encoder::compress`] produces.
+//!
+//! Once their boundaries are known, bzip2 blocks can be decoded independently
+//! of each other, whether they sit in the single stream written by `bzip2` or
+//! in the many concatenated streams written by pbzip2 and lbzip2. The input is
+//! searched for block and end-of-stream magics on worker threads, the stream
+//! layout is rebuilt from those candidates the same way [`scan`] does, and the
+//! blocks are then decoded in parallel, each wrapped in a stream of its own.
+//!
+//! libbz2 checks every block against the CRC stored in its header, and the
+//! scan checks those CRCs against the combined CRC of each stream, so a false
+//! boundary cannot slip through. When anything fails, the stream concerned and
+//! everything after it are decoded sequentially, so the result is always the
+//! same as [`decoder::decompress`] would produce.
+
+use crate::bits::BitWriter;
+use crate::control::{Control, STEP};
+use crate::decoder::{self, Decoded, Expected, Limits, OUTPUT_LIMIT_EXCEEDED};
+use crate::encoder;
+use crate::index;
+use crate::pool;
+use crate::scan::{self, Stream, EOS_MAGIC};
+use libbz2_rs_sys::BZ_DATA_ERROR_MAGIC;
+
+/// Bytes of input searched for magics per job.
+const SEARCH_CHUNK: usize = 1 << 20;
+
+/// Blocks decoded per thread between two checks of the output limit.
+const BLOCKS_PER_ROUND: usize = 2;
+
+/// Compresses `input` on up to `threads` threads. Without `single_stream`,
+/// every block becomes a stream of its own.
+pub fn compress(
+    input: &[u8],
+    block_size: i32,
+    work_factor: i32,
+    threads: usize,
+    single_stream: bool,
+    control: &Control,
+) -> Result<Vec<u8>, i32> {
+    let level = match u8::try_from(block_size) {
+        Ok(level @ 1..=9) => level,
+        _ => return encoder::compress(input, block_size, work_factor, control),
+    };
+    let mut cuts = Vec::new();
+    BlockCutter::new(level).feed(input, |cut| cuts.push(cut));
+    if cuts.is_empty() {
+        return encoder::compress(input, block_size, work_factor, control);
+    }
+    let chunks: Vec<&[u8]> = [0]
+        .into_iter()
+        .chain(cuts.iter().copied())
+        .zip(cuts.iter().copied().chain([input.len()]))
+        .map(|(start, end)| &input[start..end])
+        .collect();
+
+    if !single_stream {
+        let streams = pool::map(chunks, threads, |chunk| {
+            encoder::compress(chunk, block_size, work_factor, control)
+        });
+        return streams
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map(|streams| streams.concat());
+    }
+
+    let blocks = pool::map(chunks, threads, |chunk| {
+        CompressedBlocks::new(chunk, block_size, work_factor, control)
+    });
+    let mut splicer = Splicer::new(level);
+    for blocks in blocks {
+        splicer.push(&blocks?);
+    }
+    Ok(splicer.finish())
+}
+
+/// Finds the places where the sequential encoder would end its blocks.
+///
+/// libbz2 run-length encodes its input while filling a block, turning runs of
+/// 4 to 255 equal bytes into 5 bytes. A block is closed as soon as it holds
+/// `100_000 * level - 19` bytes, and the run still being collected at that
+/// point carries over into the next block. Replaying that bookkeeping gives
+/// chunks that each compress into exactly the block the sequential encoder
+/// would have written.
+pub struct BlockCutter {
+    capacity: usize,
+    filled: usize,
+    run_byte: Option<u8>,
+    run_len: usize,
+    /// Offset of the first byte of the current run.
+    run_start: usize,
+    /// Number of bytes fed so far.
+    pos: usize,
+}
+
+impl BlockCutter {
+    pub fn new(level: u8) -> Self {
+        Self {
+            capacity: usize::from(level) * 100_000 - 19,
+            filled: 0,
+            run_byte: None,
+            run_len: 0,
+            run_start: 0,
+            pos: 0,
+        }
+    }
+
+    /// Feeds the next bytes of input and calls `cut` with the offset of every
+    /// block boundary they reveal, counted from the first byte ever fed.
+    pub fn feed(&mut self, data: &[u8], mut cut: impl FnMut(usize)) {
+        for &byte in data {
+            if self.filled >= self.capacity {
+                cut(self.run_start);
+                self.filled = 0;
+            }
+            if self.run_byte == Some(byte) && self.run_len < 255 {
+                self.run_len += 1;
+            } else {
+                self.filled += if self.run_len < 4 { self.run_len } else { 5 };
+                self.run_byte = Some(byte);
+                self.run_len = 1;
+                self.run_start = self.pos;
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+/// A chunk of input compressed into a stream of its own, along with the
+/// location of its blocks.
+pub struct CompressedBlocks {
+    stream: Vec<u8>,
+    blocks: Vec<scan::Block>,
+}
+
+impl CompressedBlocks {
+    pub fn new(
+        chunk: &[u8],
+        block_size: i32,
+        work_factor: i32,
+        control: &Control,
+    ) -> Result<Self, i32> {
+        let stream = encoder::compress(chunk, block_size, work_factor, control)?;
+        let blocks = scan::scan(&stream)?.swap_remove(0).blocks;
+        Ok(Self { stream, blocks })
+    }
+}
+
+/// Bit-concatenates compressed blocks into a single stream.
+pub struct Splicer {
+    writer: BitWriter,
+    combined: u32,
+}
+
+impl Splicer {
+    /// Starts a stream with a `BZh` header for `level`.
+    pub fn new(level: u8) -> Self {
+        let mut writer = BitWriter::new();
+        for byte in [b'B', b'Z', b'h', b'0' + level] {
+            writer.write(u64::from(byte), 8);
+        }
+        Self {
+            writer,
+            combined: 0,
+        }
+    }
+
+    pub fn push(&mut self, compressed: &CompressedBlocks) {
+        for block in &compressed.blocks {
+            self.writer.copy_bits(
+                &compressed.stream,
+                block.bit_offset,
+                block.bit_offset + block.bit_length,
+            );
+            self.combined = scan::combine_crc(self.combined, block.crc);
+        }
+    }
+
+    /// Takes the output written so far, except for a trailing partial byte.
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        self.writer.take_bytes()
+    }
+
+    /// Ends the stream and returns whatever has not been taken yet.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.writer.write(EOS_MAGIC >> 24, 24);
+        self.writer.write(EOS_MAGIC, 24);
+        self.writer.write(u64::from(self.combined), 32);
+        self.writer.finish()
+    }
+}
+
+/// Bit offsets of every block and end-of-stream magic in `input`, in order.
+fn find_magics(input: &[u8], threads: usize, control: &Control) -> Result<Vec<(u64, u64)>, i32> {
+    let chunks: Vec<usize> = (0..input.len()).step_by(SEARCH_CHUNK).collect();
+    let magics = pool::map(chunks, threads, |start| {
+        if control.check().is_err() {
+            return Vec::new();
+        }
+        let end = (start + SEARCH_CHUNK).min(input.len());
+        // A magic starting in this chunk may run up to 6 bytes past its end.
+        let window = &input[start..(end + 6).min(input.len())];
+        let limit = (end - start) as u64 * 8;
+        let mut found = Vec::new();
+        let mut pos = 0;
+        while let Some((at, magic)) = scan::find_magic(window, pos) {
+            if at >= limit {
+                break;
+            }
+            found.push((start as u64 * 8 + at, magic));
+            pos = at + 1;
+        }
+        found
+    });
+    control.check()?;
+    Ok(magics.concat())
+}
+
+/// Decoded output, held in one buffer or, with a chunk size, in buffers of
+/// that size, which are never copied to grow.
+struct Output {
+    chunk_size: Option<usize>,
+    buffers: Vec<Vec<u8>>,
+    len: usize,
+}
+
+impl Output {
+    fn new(chunk_size: Option<usize>) -> Self {
+        Self {
+            chunk_size,
+            buffers: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn extend(&mut self, mut data: &[u8]) {
+        self.len += data.len();
+        let Some(chunk_size) = self.chunk_size else {
+            match self.buffers.first_mut() {
+                Some(buffer) => buffer.extend_from_slice(data),
+                None => self.buffers.push(data.to_vec()),
+            }
+            return;
+        };
+        while !data.is_empty() {
+            let buffer = match self.buffers.last_mut() {
+                Some(buffer) if buffer.len() < chunk_size => buffer,
+                _ => {
+                    self.buffers.push(Vec::with_capacity(chunk_size));
+                    self.buffers.last_mut().unwrap()
+                }
+            };
+            let (head, tail) = data.split_at(data.len().min(chunk_size - buffer.len()));
+            buffer.extend_from_slice(head);
+            data = tail;
+        }
+    }
+
+    /// Like [`Output::extend`], but takes over `data` if it fits in as a
+    /// buffer of its own. Otherwise it is freed as soon as it has been copied
+    /// over, so that appending chunks one at a time never holds more than one
+    /// extra chunk.
+    fn append(&mut self, data: Vec<u8>) {
+        let fits = match self.chunk_size {
+            None => self.buffers.is_empty(),
+            Some(chunk_size) => {
+                data.len() <= chunk_size
+                    && self
+                        .buffers
+                        .last()
+                        .is_none_or(|buffer| buffer.len() == chunk_size)
+            }
+        };
+        if fits && !data.is_empty() {
+            self.len += data.len();
+            self.buffers.push(data);
+        } else {
+            self.extend(&data);
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            let buffer = self.buffers.last_mut().unwrap();
+            let keep = buffer.len().saturating_sub(self.len - len);
+            self.len -= buffer.len() - keep;
+            buffer.truncate(keep);
+            if buffer.is_empty() {
+                self.buffers.pop();
+            }
+        }
+    }
+}
+
+/// Decodes `input` on up to `threads` threads. Takes the same arguments as
+/// [`decoder::decompress`] and returns the same result.
+pub fn decompress(
+    input: &[u8],
+    small: bool,
+    multi_stream: bool,
+    limits: Limits,
+    threads: usize,
+    control: &Control,
+) -> Result<Decoded, i32> {
+    let (output, consumed) = decode(
+        input,
+        small,
+        multi_stream,
+        limits,
+        threads,
+        Output::new(None),
+        control,
+    )?;
+    Ok(Decoded {
+        data: output.buffers.into_iter().next().unwrap_or_default(),
+        consumed,
+    })
+}
+
+/// Like [`decompress`], with the output in buffers of `chunk_size` bytes
+/// each apart from the last one, along with the number of input bytes
+/// consumed.
+pub fn decompress_chunks(
+    input: &[u8],
+    small: bool,
+    multi_stream: bool,
+    limits: Limits,
+    threads: usize,
+    chunk_size: usize,
+    control: &Control,
+) -> Result<(Vec<Vec<u8>>, usize), i32> {
+    let (output, consumed) = decode(
+        input,
+        small,
+        multi_stream,
+        limits,
+        threads,
+        Output::new(Some(chunk_size)),
+        control,
+    )?;
+    Ok((output.buffers, consumed))
+}
+
+fn decode(
+    input: &[u8],
+    small: bool,
+    multi_stream: bool,
+    limits: Limits,
+    threads: usize,
+    mut output: Output,
+    control: &Control,
+) -> Result<(Output, usize), i32> {
+    let magics = find_magics(input, threads, control)?;
+    let next_magic = |start: u64| {
+        let i = magics.partition_point(|&(at, _)| at < start);
+        magics.get(i).copied()
+    };
+
+    let mut streams: Vec<Stream> = Vec::new();
+    let mut offset = 0;
+    while offset < input.len() && (multi_stream || streams.is_empty()) {
+        let Some(level) = scan::parse_header(input, offset) else {
+            break;
+        };
+        match scan::scan_stream(input, offset, level, next_magic) {
+            Ok(stream) => {
+                offset += stream.length;
+                streams.push(stream);
+            }
+            Err(_) => break,
+        }
+    }
+    // Whatever could not be scanned is left to the sequential decoder, which
+    // either reports the right error or recognises it as trailing garbage.
+    // Anything shorter than a stream header is garbage in any case.
+    let tail = streams.is_empty() || (multi_stream && input.len() - offset >= scan::HEADER_LEN);
+
+    let max_output = limits.max_output(input.len() as u64);
+
+    let blocks: Vec<(&Stream, &scan::Block)> = streams
+        .iter()
+        .flat_map(|stream| stream.blocks.iter().map(move |block| (stream, block)))
+        .collect();
+    let mut stream_offset = 0;
+    let mut stream_output = 0;
+    for round in blocks.chunks(threads * BLOCKS_PER_ROUND) {
+        control.check()?;
+        let decoded = pool::map(round.to_vec(), threads, |(stream, block)| {
+            index::decode_block(
+                input,
+                block.bit_offset,
+                block.bit_length,
+                stream.level,
+                small,
+                control,
+            )
+        });
+        for (&(stream, _), result) in round.iter().zip(decoded) {
+            if stream.offset != stream_offset {
+                stream_offset = stream.offset;
+                stream_output = output.len;
+            }
+            let Ok(data) = result else {
+                output.truncate(stream_output);
+                return finish(
+                    input,
+                    stream_offset,
+                    output,
+                    small,
+                    multi_stream,
+                    max_output,
+                    control,
+                );
+            };
+            if (output.len + data.len()) as u64 > max_output {
+                return Err(OUTPUT_LIMIT_EXCEEDED);
+            }
+            output.extend(&data);
+        }
+    }
+
+    if tail {
+        return finish(
+            input,
+            offset,
+            output,
+            small,
+            multi_stream,
+            max_output,
+            control,
+        );
+    }
+    Ok((output, offset))
+}
+
+/// Decodes everything from byte `start` on sequentially and appends it to
+/// `output`, which holds the streams before `start`.
+fn finish(
+    input: &[u8],
+    start: usize,
+    mut output: Output,
+    small: bool,
+    multi_stream: bool,
+    max_output: u64,
+    control: &Control,
+) -> Result<(Output, usize), i32> {
+    let input = &input[start..];
+    let limits = Limits {
+        max_output_size: Some(max_output - output.len as u64),
+        max_ratio: None,
+    };
+    let mut decompression = decoder::Decompression::new(
+        input.len(),
+        small,
+        multi_stream,
+        limits,
+        Expected::default(),
+        output.chunk_size,
+    )?;
+    let result = loop {
+        match decompression.step(input, STEP, control) {
+            Ok(false) => {}
+            Ok(true) => break Ok(()),
+            Err(code) => break Err(code),
+        }
+    };
+    match result {
+        Ok(()) => {
+            let (chunks, consumed) = decompression.into_chunks();
+            for chunk in chunks {
+                output.append(chunk);
+            }
+            Ok((output, start + consumed))
+        }
+        Err(BZ_DATA_ERROR_MAGIC) if start > 0 => Ok((output, start)),
+        Err(code) => Err(code),
+    }
+}
@@ -0,0 +1,115 @@
+//! Streaming compression that encodes blocks on the worker pool.
+//!
+//! Input is buffered until it fills a block, using the same cut points as
+//! [`parallel::compress`](crate::parallel::compress), and every full block is
+//! handed to a worker. Blocks come back in order and are spliced into one
+//! stream, so the output is identical to what a single
+//! [`Encoder`](crate::encoder::Encoder) would produce, only split differently
+//! across calls.
+
+use crate::control::Control;
+use crate::parallel::{BlockCutter, CompressedBlocks, Splicer};
+use crate::pool::{self, Task};
+use libbz2_rs_sys::BZ_PARAM_ERROR;
+use std::collections::VecDeque;
+
+pub struct Encoder {
+    block_size: i32,
+    work_factor: i32,
+    /// Maximum number of blocks being compressed at once.
+    threads: usize,
+    cutter: BlockCutter,
+    /// Input that has not been handed to a worker yet.
+    buffer: Vec<u8>,
+    /// Offset of `buffer[0]` in the whole input.
+    buffer_start: usize,
+    /// Blocks being compressed, oldest first.
+    running: VecDeque<Task<Result<CompressedBlocks, i32>>>,
+    splicer: Splicer,
+    control: Control,
+}
+
+impl Encoder {
+    pub fn new(
+        block_size: i32,
+        work_factor: i32,
+        threads: usize,
+        control: Control,
+    ) -> Result<Self, i32> {
+        let level = match u8::try_from(block_size) {
+            Ok(level @ 1..=9) => level,
+            _ => return Err(BZ_PARAM_ERROR),
+        };
+        if !(0..=250).contains(&work_factor) || threads == 0 {
+            return Err(BZ_PARAM_ERROR);
+        }
+        Ok(Self {
+            block_size,
+            work_factor,
+            threads,
+            cutter: BlockCutter::new(level),
+            buffer: Vec::new(),
+            buffer_start: 0,
+            running: VecDeque::new(),
+            splicer: Splicer::new(level),
+            control,
+        })
+    }
+
+    /// Buffers `input`, starts compressing every block it completes and
+    /// returns the output of the blocks that are done so far.
+    pub fn write(&mut self, input: &[u8]) -> Result<Vec<u8>, i32> {
+        self.control.check()?;
+        let mut cuts = Vec::new();
+        self.cutter.feed(input, |cut| cuts.push(cut));
+        self.buffer.extend_from_slice(input);
+
+        let mut taken = 0;
+        for cut in cuts {
+            let end = cut - self.buffer_start;
+            let chunk = self.buffer[taken..end].to_vec();
+            taken = end;
+            if self.running.len() >= self.threads {
+                self.collect_oldest()?;
+            }
+            self.start(chunk);
+        }
+        self.buffer.drain(..taken);
+        self.buffer_start += taken;
+
+        while self.running.front().is_some_and(Task::is_done) {
+            self.collect_oldest()?;
+        }
+        Ok(self.splicer.take_bytes())
+    }
+
+    /// Compresses whatever is still buffered, waits for every block and
+    /// returns the rest of the stream.
+    pub fn finish(mut self) -> Result<Vec<u8>, i32> {
+        self.control.check()?;
+        if !self.buffer.is_empty() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.start(chunk);
+        }
+        while !self.running.is_empty() {
+            self.collect_oldest()?;
+        }
+        Ok(self.splicer.finish())
+    }
+
+    fn start(&mut self, chunk: Vec<u8>) {
+        let (block_size, work_factor) = (self.block_size, self.work_factor);
+        let control = self.control.clone();
+        self.running.push_back(pool::spawn(move || {
+            CompressedBlocks::new(&chunk, block_size, work_factor, &control)
+        }));
+    }
+
+    fn collect_oldest(&mut self) -> Result<(), i32> {
+        self.control.check()?;
+        if let Some(task) = self.running.pop_front() {
+            self.splicer.push(&task.wait()?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+//! Worker threads shared by the parallel NIFs.
+//!
+//! Work is handed out with [`map`], which runs a closure over a list of items
+//! and blocks until every item is done. The calling thread takes items too, so
+//! a batch always makes progress even when every worker is busy elsewhere.
+//! Jobs that should keep running after the caller returns go through
+//! [`spawn`] instead.
+//!
+//! The pool defaults to one thread per CPU. Its size can be set when the NIF
+//! library is loaded and changed at any time with [`set_size`]. Threads are
+//! only started once there is work for them; when the pool shrinks, surplus
+//! workers exit after their current job.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct State {
+    queue: VecDeque<Job>,
+    /// Number of workers the pool should have.
+    size: usize,
+    /// Number of workers currently alive.
+    workers: usize,
+    /// Number of workers currently running a job.
+    active: usize,
+    /// Number of workers ever started, used to name new ones.
+    spawned: usize,
+}
+
+struct Pool {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+fn pool() -> &'static Pool {
+    POOL.get_or_init(|| Pool {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            size: thread::available_parallelism().map_or(1, |n| n.get()),
+            workers: 0,
+            active: 0,
+            spawned: 0,
+        }),
+        available: Condvar::new(),
+    })
+}
+
+/// Snapshot of the pool for monitoring.
+pub struct Stats {
+    pub size: usize,
+    pub workers: usize,
+    pub active: usize,
+    pub queued: usize,
+}
+
+pub fn stats() -> Stats {
+    let state = pool().state.lock().unwrap();
+    Stats {
+        size: state.size,
+        workers: state.workers,
+        active: state.active,
+        queued: state.queue.len(),
+    }
+}
+
+pub fn size() -> usize {
+    pool().state.lock().unwrap().size
+}
+
+/// Changes the number of worker threads. `size` must be at least 1.
+pub fn set_size(size: usize) {
+    let pool = pool();
+    let mut state = pool.state.lock().unwrap();
+    state.size = size;
+    if !state.queue.is_empty() {
+        start_workers(pool, &mut state);
+    }
+    drop(state);
+    // Idle workers above the new size wake up and exit.
+    pool.available.notify_all();
+}
+
+fn start_workers(pool: &'static Pool, state: &mut State) {
+    while state.workers < state.size {
+        let name = format!("bz2_ex-worker-{}", state.spawned);
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || worker(pool))
+            .expect("failed to spawn bz2_ex worker thread");
+        state.workers += 1;
+        state.spawned += 1;
+    }
+}
+
+fn worker(pool: &Pool) {
+    loop {
+        let job = {
+            let mut state = pool.state.lock().unwrap();
+            loop {
+                if state.workers > state.size {
+                    state.workers -= 1;
+                    return;
+                }
+                match state.queue.pop_front() {
+                    Some(job) => {
+                        state.active += 1;
+                        break job;
+                    }
+                    None => state = pool.available.wait(state).unwrap(),
+                }
+            }
+        };
+        // Jobs catch their own panics; this only keeps the worker alive if
+        // one slips through.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        pool.state.lock().unwrap().active -= 1;
+    }
+}
+
+fn submit(job: Job) {
+    let pool = pool();
+    let mut state = pool.state.lock().unwrap();
+    state.queue.push_back(job);
+    start_workers(pool, &mut state);
+    drop(state);
+    pool.available.notify_one();
+}
+
+/// State shared between the caller of [`map`] and the helper jobs it queued.
+struct Batch {
+    next: AtomicUsize,
+    len: usize,
+    done: Mutex<usize>,
+    all_done: Condvar,
+    /// Points at the caller's per-item closure. Only dereferenced after
+    /// claiming an index below `len`; the caller does not return until every
+    /// such index is done, so the closure outlives every use.
+    run: *const (dyn Fn(usize) + Sync),
+}
+
+unsafe impl Send for Batch {}
+unsafe impl Sync for Batch {}
+
+impl Batch {
+    fn work(&self) {
+        loop {
+            let i = self.next.fetch_add(1, Ordering::Relaxed);
+            if i >= self.len {
+                return;
+            }
+            unsafe { (*self.run)(i) };
+            let mut done = self.done.lock().unwrap();
+            *done += 1;
+            if *done == self.len {
+                self.all_done.notify_all();
+            }
+        }
+    }
+}
+
+/// Applies `f` to every item on up to `threads` threads, the caller included,
+/// and returns the results in order. A panic in `f` is re-raised on the
+/// calling thread once every item has finished.
+pub fn map<T, R, F>(items: Vec<T>, threads: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let len = items.len();
+    let helpers = threads.min(len).min(size() + 1).saturating_sub(1);
+    if helpers == 0 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let slots: Vec<Mutex<Option<T>>> = items
+        .into_iter()
+        .map(|item| Mutex::new(Some(item)))
+        .collect();
+    let results: Vec<Mutex<Option<thread::Result<R>>>> =
+        (0..len).map(|_| Mutex::new(None)).collect();
+    let run = |i: usize| {
+        let item = slots[i].lock().unwrap().take().unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)));
+        *results[i].lock().unwrap() = Some(result);
+    };
+    let run: &(dyn Fn(usize) + Sync) = &run;
+
+    let batch = Arc::new(Batch {
+        next: AtomicUsize::new(0),
+        len,
+        done: Mutex::new(0),
+        all_done: Condvar::new(),
+        // Erase the closure's lifetime so helpers can be queued as 'static
+        // jobs. See `Batch::run` for why this is sound.
+        run: unsafe {
+            std::mem::transmute::<*const (dyn Fn(usize) + Sync + '_), *const (dyn Fn(usize) + Sync)>(
+                run,
+            )
+        },
+    });
+
+    for _ in 0..helpers {
+        let batch = Arc::clone(&batch);
+        submit(Box::new(move || batch.work()));
+    }
+    batch.work();
+
+    let mut done = batch.done.lock().unwrap();
+    while *done < len {
+        done = batch.all_done.wait(done).unwrap();
+    }
+    drop(done);
+
+    results
+        .into_iter()
+        .map(|slot| match slot.into_inner().unwrap().unwrap() {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        })
+        .collect()
+}
+
+/// Runs `f` on a worker thread and forgets about it.
+pub fn execute<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    submit(Box::new(f));
+}
+
+/// Handle to a job started with [`spawn`].
+pub struct Task<T> {
+    state: Arc<(Mutex<Option<thread::Result<T>>>, Condvar)>,
+}
+
+impl<T> Task<T> {
+    pub fn is_done(&self) -> bool {
+        self.state.0.lock().unwrap().is_some()
+    }
+
+    /// Blocks until the job has finished and returns its result. A panic in
+    /// the job is re-raised here.
+    pub fn wait(self) -> T {
+        let (result, finished) = &*self.state;
+        let mut result = result.lock().unwrap();
+        loop {
+            match result.take() {
+                Some(Ok(value)) => return value,
+                Some(Err(payload)) => panic::resume_unwind(payload),
+                None => result = finished.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+/// Runs `f` on a worker thread without waiting for it.
+pub fn spawn<T, F>(f: F) -> Task<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let state = Arc::new((Mutex::new(None), Condvar::new()));
+    let task = Task {
+        state: Arc::clone(&state),
+    };
+    submit(Box::new(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let (slot, finished) = &*state;
+        *slot.lock().unwrap() = Some(result);
+        finished.notify_all();
+    }));
+    task
+}
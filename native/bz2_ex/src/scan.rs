@@ -0,0 +1,179 @@
+//! Walks the stream and block layout of bzip2 data without decoding it.
+//!
+//! Block boundaries are not recorded anywhere in the format: a block simply
+//! ends where the next block magic or end-of-stream magic begins, at any bit
+//! alignment. Like `bzip2recover`, we find them by searching for those 48-bit
+//! patterns. A candidate block start has to carry a plausible block header,
+//! and a candidate end-of-stream marker has to carry the combined CRC of the
+//! blocks before it, which rules out accidental matches inside compressed
+//! data for all practical purposes.
+
+use crate::bits::{bit_len, find_pattern48, read_bits};
+use libbz2_rs_sys::{BZ_DATA_ERROR, BZ_DATA_ERROR_MAGIC, BZ_UNEXPECTED_EOF};
+
+pub const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+pub const EOS_MAGIC: u64 = 0x1772_4538_5090;
+
+/// Size of the `BZh1`..`BZh9` stream header in bytes.
+pub const HEADER_LEN: usize = 4;
+
+pub struct Block {
+    /// Bit offset of the block magic from the start of the input.
+    pub bit_offset: u64,
+    /// Length of the block in bits, from its magic up to the next magic.
+    pub bit_length: u64,
+    /// Block CRC as stored in the block header.
+    pub crc: u32,
+}
+
+pub struct Stream {
+    /// Byte offset of the `BZh` header.
+    pub offset: usize,
+    /// Length in bytes, including the padding after the end-of-stream marker.
+    pub length: usize,
+    /// Block size digit from the header, `1..=9`.
+    pub level: u8,
+    pub blocks: Vec<Block>,
+    /// Combined CRC as stored after the end-of-stream marker.
+    pub crc: u32,
+}
+
+/// Folds a block CRC into a stream's combined CRC.
+pub fn combine_crc(combined: u32, block_crc: u32) -> u32 {
+    combined.rotate_left(1) ^ block_crc
+}
+
+/// Returns the block size digit if a stream header starts at `offset`.
+pub fn parse_header(data: &[u8], offset: usize) -> Option<u8> {
+    match data.get(offset..offset + HEADER_LEN)? {
+        [b'B', b'Z', b'h', level @ b'1'..=b'9'] => Some(level - b'0'),
+        _ => None,
+    }
+}
+
+/// Checks that the block header starting at bit `pos` could have been written
+/// by an encoder using block size `level`.
+fn plausible_block(data: &[u8], pos: u64, level: u8) -> bool {
+    // magic, block CRC, randomised flag
+    let mut p = pos + 48 + 32 + 1;
+
+    let orig_ptr = read_bits(data, p, 24);
+    p += 24;
+    if orig_ptr >= 100_000 * u64::from(level) {
+        return false;
+    }
+
+    let used = read_bits(data, p, 16);
+    p += 16;
+    if used == 0 {
+        return false;
+    }
+    for i in 0..16 {
+        if used & (1 << (15 - i)) != 0 {
+            if read_bits(data, p, 16) == 0 {
+                return false;
+            }
+            p += 16;
+        }
+    }
+
+    let groups = read_bits(data, p, 3);
+    let selectors = read_bits(data, p + 3, 15);
+    p += 18;
+
+    (2..=6).contains(&groups) && selectors > 0 && p <= bit_len(data)
+}
+
+/// Finds the first block or end-of-stream magic at or after bit `start`.
+pub fn find_magic(data: &[u8], start: u64) -> Option<(u64, u64)> {
+    find_pattern48(data, start, &[BLOCK_MAGIC, EOS_MAGIC])
+}
+
+/// Walks the stream whose header starts at byte `offset`. `next_magic` has to
+/// behave like [`find_magic`]; it lets callers substitute a search over magics
+/// they located up front.
+pub fn scan_stream(
+    data: &[u8],
+    offset: usize,
+    level: u8,
+    mut next_magic: impl FnMut(u64) -> Option<(u64, u64)>,
+) -> Result<Stream, i32> {
+    let total = bit_len(data);
+    let mut pos = (offset + HEADER_LEN) as u64 * 8;
+    let mut blocks = Vec::new();
+    let mut combined = 0u32;
+
+    loop {
+        if pos + 80 > total {
+            return Err(BZ_UNEXPECTED_EOF);
+        }
+        let magic = read_bits(data, pos, 48);
+        let crc = read_bits(data, pos + 48, 32) as u32;
+
+        if magic == EOS_MAGIC {
+            if crc != combined {
+                return Err(BZ_DATA_ERROR);
+            }
+            let end = (pos + 80).div_ceil(8) as usize;
+            return Ok(Stream {
+                offset,
+                length: end - offset,
+                level,
+                blocks,
+                crc,
+            });
+        }
+        if magic != BLOCK_MAGIC {
+            return Err(BZ_DATA_ERROR);
+        }
+
+        let expected = combine_crc(combined, crc);
+        let mut search = pos + 80;
+        let next = loop {
+            match next_magic(search) {
+                None => return Err(BZ_UNEXPECTED_EOF),
+                Some((p, BLOCK_MAGIC)) if plausible_block(data, p, level) => break p,
+                Some((p, EOS_MAGIC))
+                    if p + 80 <= total && read_bits(data, p + 48, 32) as u32 == expected =>
+                {
+                    break p
+                }
+                Some((p, _)) => search = p + 1,
+            }
+        };
+
+        blocks.push(Block {
+            bit_offset: pos,
+            bit_length: next - pos,
+            crc,
+        });
+        combined = expected;
+        pos = next;
+    }
+}
+
+/// Lists every stream in `data` along with its blocks.
+///
+/// As with decompression, anything after the last stream that does not start
+/// with a stream header is ignored.
+pub fn scan(data: &[u8]) -> Result<Vec<Stream>, i32> {
+    let mut streams = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let Some(level) = parse_header(data, offset) else {
+            if streams.is_empty() {
+                return Err(BZ_DATA_ERROR_MAGIC);
+            }
+            break;
+        };
+        let stream = scan_stream(data, offset, level, |start| find_magic(data, start))?;
+        offset += stream.length;
+        streams.push(stream);
+    }
+
+    if streams.is_empty() {
+        return Err(BZ_UNEXPECTED_EOF);
+    }
+    Ok(streams)
+}
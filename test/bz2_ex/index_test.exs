@@ -0,0 +1,144 @@
+defmodule Bz2Ex.IndexTest do
+  use ExUnit.Case, async: true
+
+  setup do
+    original = :crypto.strong_rand_bytes(350_000)
+    [original: original, compressed: Bz2Ex.compress!(original, block_size: 1)]
+  end
+
+  describe "build/2" do
+    test "indexes every block", %{original: original, compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      info = Bz2Ex.Index.info(index)
+
+      assert info.block_size == 1
+      assert info.uncompressed_size == byte_size(original)
+      assert length(info.blocks) == 4
+      assert Enum.map(info.blocks, & &1.uncompressed_size) |> Enum.sum() == byte_size(original)
+      assert hd(info.blocks).bit_offset == 32
+    end
+
+    test "indexes concatenated streams" do
+      compressed = Bz2Ex.compress!("first", block_size: 1) <> Bz2Ex.compress!("second", block_size: 9)
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+
+      assert %{block_size: 9, uncompressed_size: 11, blocks: [_, _]} = Bz2Ex.Index.info(index)
+      assert {:ok, "stsec"} = Bz2Ex.Index.read(index, compressed, 3, 5)
+    end
+
+    test "returns error for invalid data" do
+      {:error, :data_error_magic} = Bz2Ex.Index.build(<<1, 2, 3, 4, 5>>)
+    end
+  end
+
+  describe "read/5" do
+    test "reads ranges inside and across blocks", %{original: original, compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+
+      for {offset, length} <- [{0, 10}, {50_000, 1024}, {99_000, 5_000}, {120_000, 200_000}] do
+        {:ok, chunk} = Bz2Ex.Index.read(index, compressed, offset, length)
+        assert chunk == binary_part(original, offset, length)
+      end
+    end
+
+    test "cuts ranges short at the end", %{original: original, compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+
+      {:ok, chunk} = Bz2Ex.Index.read(index, compressed, 349_990, 100)
+      assert chunk == binary_part(original, 349_990, 10)
+      {:ok, ""} = Bz2Ex.Index.read(index, compressed, 400_000, 100)
+    end
+
+    test "rejects data the index was not built from", %{compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      other = Bz2Ex.compress!(:crypto.strong_rand_bytes(350_000), block_size: 1)
+
+      {:error, :data_error} = Bz2Ex.Index.read(index, other, 0, 10)
+    end
+
+    test "rejects blocks past the end of the data", %{original: original, compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+
+      moved =
+        index
+        |> Bz2Ex.Index.encode()
+        |> tamper(fn entries -> List.update_at(entries, -1, fn {_, l, r} -> {8 * byte_size(compressed), l, r} end) end)
+
+      {:ok, index} = Bz2Ex.Index.decode(moved)
+      {:error, :invalid_index} = Bz2Ex.Index.read(index, compressed, 349_990, 10)
+      {:ok, chunk} = Bz2Ex.Index.read(index, compressed, 0, 10)
+      assert chunk == binary_part(original, 0, 10)
+    end
+  end
+
+  describe "encode/1 and decode/1" do
+    test "round-trip an index", %{original: original, compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      encoded = Bz2Ex.Index.encode(index)
+      assert <<"BZIX", 1, _::binary>> = encoded
+
+      {:ok, decoded} = Bz2Ex.Index.decode(encoded)
+      assert Bz2Ex.Index.info(decoded) == Bz2Ex.Index.info(index)
+      {:ok, chunk} = Bz2Ex.Index.read(decoded, compressed, 200_000, 100)
+      assert chunk == binary_part(original, 200_000, 100)
+    end
+
+    test "rejects corrupted data", %{compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      <<head::binary-size(30), byte, tail::binary>> = Bz2Ex.Index.encode(index)
+
+      {:error, :invalid_index} = Bz2Ex.Index.decode(<<head::binary, Bitwise.bxor(byte, 1), tail::binary>>)
+    end
+
+    test "rejects truncated data", %{compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      encoded = Bz2Ex.Index.encode(index)
+
+      {:error, :invalid_index} = Bz2Ex.Index.decode(binary_part(encoded, 0, byte_size(encoded) - 1))
+      {:error, :invalid_index} = Bz2Ex.Index.decode("not an index")
+    end
+
+    test "rejects blocks whose end overflows", %{compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      encoded = Bz2Ex.Index.encode(index)
+
+      overflowing = tamper(encoded, fn [{o, _, r} | rest] -> [{o, 0xFFFF_FFFF_FFFF_FFFF, r} | rest] end)
+      {:error, :invalid_index} = Bz2Ex.Index.decode(overflowing)
+
+      overlapping = tamper(encoded, fn [{o, l, r} | rest] -> [{o, l + 8, r} | rest] end)
+      {:error, :invalid_index} = Bz2Ex.Index.decode(overlapping)
+    end
+
+    test "rejects unknown versions", %{compressed: compressed} do
+      {:ok, index} = Bz2Ex.Index.build(compressed)
+      <<"BZIX", _version, rest::binary>> = Bz2Ex.Index.encode(index)
+
+      {:error, :unsupported_version} = Bz2Ex.Index.decode(<<"BZIX", 2, rest::binary>>)
+    end
+  end
+
+  # Rewrites the {bit_offset, bit_length, rest} entries of an encoded index
+  # and recomputes its checksum.
+  defp tamper(encoded, fun) do
+    <<header::binary-size(24), entries::binary>> = binary_part(encoded, 0, byte_size(encoded) - 4)
+    entries = for <<o::64, l::64, r::binary-size(12) <- entries>>, do: {o, l, r}
+    body = IO.iodata_to_binary([header | for({o, l, r} <- fun.(entries), do: <<o::64, l::64, r::binary>>)])
+    body <> <<checksum(body)::32>>
+  end
+
+  # The bzip2 CRC-32 the index format is checksummed with.
+  defp checksum(data) do
+    import Bitwise
+
+    crc =
+      for <<byte <- data>>, reduce: 0xFFFFFFFF do
+        crc ->
+          Enum.reduce(1..8, bxor(crc, byte <<< 24), fn _, crc ->
+            shifted = crc <<< 1 &&& 0xFFFFFFFF
+            if (crc &&& 0x80000000) != 0, do: bxor(shifted, 0x04C11DB7), else: shifted
+          end)
+      end
+
+    bxor(crc, 0xFFFFFFFF)
+  end
+end
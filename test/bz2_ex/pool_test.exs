@@ -0,0 +1,30 @@
+defmodule Bz2Ex.PoolTest do
+  use ExUnit.Case, async: false
+
+  setup do
+    size = Bz2Ex.Pool.size()
+    on_exit(fn -> Bz2Ex.Pool.set_size(size) end)
+  end
+
+  test "changes the pool size" do
+    :ok = Bz2Ex.Pool.set_size(2)
+    assert Bz2Ex.Pool.size() == 2
+    assert %{size: 2} = Bz2Ex.Pool.stats()
+  end
+
+  test "reports worker statistics" do
+    %{workers: workers, active: active, queued: queued} = Bz2Ex.Pool.stats()
+    assert is_integer(workers) and is_integer(active) and is_integer(queued)
+  end
+
+  test "keeps working after resizing" do
+    :ok = Bz2Ex.Pool.set_size(1)
+    original = :crypto.strong_rand_bytes(300_000)
+    compressed = Bz2Ex.compress!(original, block_size: 1, threads: 4)
+    {:ok, ^original} = Bz2Ex.decompress(compressed, threads: 4)
+  end
+
+  test "raises on invalid size" do
+    assert_raise ArgumentError, fn -> Bz2Ex.Pool.set_size(0) end
+  end
+end
@@ -35,6 +35,75 @@ defmodule Bz2Ex.StreamTest do
       {:ok, decompressed} = Bz2Ex.decompress(compressed)
       assert decompressed == original
     end
+
+    test "keeps all output when a small chunk completes a block" do
+      {:ok, stream} = Bz2Ex.Stream.compress_init(block_size: 1)
+      original = :crypto.strong_rand_bytes(120_000)
+      <<first::binary-size(99_000), second::binary>> = original
+
+      {:ok, c1, stream} = Bz2Ex.Stream.compress(stream, first)
+      {:ok, c2, stream} = Bz2Ex.Stream.compress(stream, second)
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+
+      {:ok, decompressed} = Bz2Ex.decompress(IO.iodata_to_binary([c1, c2, final]))
+      assert decompressed == original
+    end
+
+    test "does not interleave concurrent calls on the same stream" do
+      {:ok, stream} = Bz2Ex.Stream.compress_init(block_size: 9)
+      # Together these fit in one block, so no call returns any output and
+      # the order the calls ran in does not matter.
+      inputs = for _ <- 1..8, do: :crypto.strong_rand_bytes(100_000)
+
+      outputs =
+        inputs
+        |> Enum.map(fn input -> Task.async(fn -> Bz2Ex.Stream.compress(stream, input) end) end)
+        |> Enum.map(&Task.await(&1, 30_000))
+
+      assert Enum.all?(outputs, &match?({:ok, "", _}, &1))
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+
+      {:ok, decompressed} = Bz2Ex.decompress(final)
+      pieces = for <<piece::binary-size(100_000) <- decompressed>>, do: piece
+      assert Enum.sort(pieces) == Enum.sort(inputs)
+    end
+  end
+
+  describe "parallel compression streaming" do
+    setup do
+      [original: :crypto.strong_rand_bytes(20_000) |> :binary.copy(20)]
+    end
+
+    test "produces the same output as a single thread", %{original: original} do
+      {:ok, stream} = Bz2Ex.Stream.compress_init(block_size: 1, threads: 4)
+
+      {compressed_chunks, stream} =
+        for(<<chunk::binary-size(30_000) <- original>>, do: chunk)
+        |> Enum.map_reduce(stream, fn chunk, s ->
+          {:ok, out, s} = Bz2Ex.Stream.compress(s, chunk)
+          {out, s}
+        end)
+
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+      compressed = IO.iodata_to_binary(compressed_chunks ++ [final])
+      assert compressed == Bz2Ex.compress!(original, block_size: 1)
+    end
+
+    test "handles empty input" do
+      {:ok, stream} = Bz2Ex.Stream.compress_init(threads: 2)
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+      assert final == Bz2Ex.compress!("", block_size: 9)
+    end
+
+    test "cannot be used after finishing" do
+      {:ok, stream} = Bz2Ex.Stream.compress_init(threads: 2)
+      {:ok, _} = Bz2Ex.Stream.compress_finish(stream)
+      {:error, :sequence_error} = Bz2Ex.Stream.compress(stream, "more")
+    end
+
+    test "raises on invalid threads" do
+      assert_raise ArgumentError, fn -> Bz2Ex.Stream.compress_init(threads: 0) end
+    end
   end
 
   describe "decompression streaming" do
@@ -66,6 +135,58 @@ defmodule Bz2Ex.StreamTest do
       {:ok, stream} = Bz2Ex.Stream.decompress_init()
       {:error, :data_error_magic} = Bz2Ex.Stream.decompress(stream, <<1, 2, 3>>)
     end
+
+    test "enforces max_output_size across calls" do
+      compressed = Bz2Ex.compress!(String.duplicate("a", 100_000))
+      split = div(byte_size(compressed), 2)
+      c1 = binary_part(compressed, 0, split)
+      c2 = binary_part(compressed, split, byte_size(compressed) - split)
+
+      {:ok, stream} = Bz2Ex.Stream.decompress_init(max_output_size: 50_000)
+      {:ok, _, :ready, stream} = Bz2Ex.Stream.decompress(stream, c1)
+      {:error, :output_limit_exceeded} = Bz2Ex.Stream.decompress(stream, c2)
+    end
+
+    test "enforces max_ratio" do
+      compressed = Bz2Ex.compress!(String.duplicate("a", 100_000))
+      {:ok, stream} = Bz2Ex.Stream.decompress_init(max_ratio: 10)
+      {:error, :output_limit_exceeded} = Bz2Ex.Stream.decompress(stream, compressed)
+    end
+
+    test "returns input after the end of the stream" do
+      compressed = Bz2Ex.compress!("Hello, World!")
+      split = div(byte_size(compressed), 2)
+      c1 = binary_part(compressed, 0, split)
+      c2 = binary_part(compressed, split, byte_size(compressed) - split)
+
+      {:ok, stream} = Bz2Ex.Stream.decompress_init()
+      {:ok, d1, :ready, "", stream} = Bz2Ex.Stream.decompress_with_rest(stream, c1)
+      {:ok, d2, :finished, "trailer", _} = Bz2Ex.Stream.decompress_with_rest(stream, c2 <> "trailer")
+      assert IO.iodata_to_binary([d1, d2]) == "Hello, World!"
+    end
+  end
+
+  describe "cancellation" do
+    test "closes compression streams" do
+      for threads <- [1, 2] do
+        handle = Bz2Ex.CancelHandle.new()
+        {:ok, stream} = Bz2Ex.Stream.compress_init(threads: threads, cancel: handle)
+        {:ok, _, stream} = Bz2Ex.Stream.compress(stream, "Hello, ")
+        :ok = Bz2Ex.CancelHandle.cancel(handle)
+        {:error, :cancelled} = Bz2Ex.Stream.compress(stream, "World!")
+        {:error, :sequence_error} = Bz2Ex.Stream.compress_finish(stream)
+      end
+    end
+
+    test "closes decompression streams" do
+      compressed = Bz2Ex.compress!("Hello, World!")
+      handle = Bz2Ex.CancelHandle.new()
+      {:ok, stream} = Bz2Ex.Stream.decompress_init(cancel: handle)
+      {:ok, _, :ready, stream} = Bz2Ex.Stream.decompress(stream, binary_part(compressed, 0, 10))
+      :ok = Bz2Ex.CancelHandle.cancel(handle)
+      {:error, :cancelled} = Bz2Ex.Stream.decompress(stream, binary_part(compressed, 10, byte_size(compressed) - 10))
+      {:error, :sequence_error} = Bz2Ex.Stream.decompress(stream, "")
+    end
   end
 
   describe "interoperability" do
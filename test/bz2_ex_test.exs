@@ -35,6 +35,121 @@ defmodule Bz2ExTest do
       {:ok, compressed} = Bz2Ex.compress(<<>>)
       assert is_binary(compressed)
     end
+
+    test "picks the smallest block_size that fits when none is given" do
+      assert <<"BZh1", _::binary>> = Bz2Ex.compress!("{\"small\": true}")
+      assert <<"BZh2", _::binary>> = Bz2Ex.compress!(:crypto.strong_rand_bytes(150_000))
+      assert <<"BZh9", _::binary>> = Bz2Ex.compress!(:crypto.strong_rand_bytes(1_000_000))
+      assert <<"BZh9", _::binary>> = Bz2Ex.compress!("{\"small\": true}", block_size: 9)
+    end
+
+    test "only the header differs from an explicit block_size of 9" do
+      data = String.duplicate("abc", 500)
+      <<"BZh1", body::binary>> = Bz2Ex.compress!(data)
+      assert <<"BZh9", ^body::binary>> = Bz2Ex.compress!(data, block_size: 9)
+    end
+  end
+
+  describe "iodata input" do
+    test "compresses iolists the same as the flattened binary" do
+      small = ["{", [?", "id", ?"], ": 1" | "}"]
+      assert Bz2Ex.compress!(small) == Bz2Ex.compress!(IO.iodata_to_binary(small))
+
+      data = :binary.copy("0123456789abcdef", 20_000) <> :crypto.strong_rand_bytes(100_000)
+      parts = for <<part::binary-size(10_000) <- data>>, do: part
+      iolist = [Enum.take(parts, 20), ?x, [[Enum.drop(parts, 20)], 1, 2 | "tail"]]
+      flat = IO.iodata_to_binary(iolist)
+
+      for opts <- [[], [block_size: 1], [threads: 4], [threads: 4, single_stream: true]] do
+        assert Bz2Ex.compress!(iolist, opts) == Bz2Ex.compress!(flat, opts)
+      end
+
+      # Binaries this small live on the process heap rather than off it.
+      heap = for i <- 1..2_000, do: "line #{i}\n"
+      assert Bz2Ex.compress!(heap) == Bz2Ex.compress!(IO.iodata_to_binary(heap))
+    end
+
+    test "feeds iolists into compression streams" do
+      {:ok, stream} = Bz2Ex.Stream.compress_init()
+      {:ok, c1, stream} = Bz2Ex.Stream.compress(stream, ["Hel", ?l, [?o, ", "]])
+      {:ok, c2, stream} = Bz2Ex.Stream.compress(stream, [[], "World" | "!"])
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+
+      assert Bz2Ex.decompress!(IO.iodata_to_binary([c1, c2, final])) == "Hello, World!"
+    end
+
+    test "raises on invalid iodata" do
+      assert_raise ArgumentError, fn -> Bz2Ex.compress(["ok", :atom]) end
+      assert_raise ArgumentError, fn -> Bz2Ex.compress([256]) end
+
+      {:ok, stream} = Bz2Ex.Stream.compress_init()
+      assert_raise ArgumentError, fn -> Bz2Ex.Stream.compress(stream, ["ok" | 1]) end
+    end
+  end
+
+  describe "small inputs" do
+    test "round-trip with any options" do
+      data = ~s({"id": 1, "tags": ["a", "b"]})
+
+      for opts <- [[], [threads: 4], [threads: 4, single_stream: true], [block_size: 9]] do
+        compressed = Bz2Ex.compress!(data, opts)
+        assert Bz2Ex.decompress!(compressed) == data
+        assert Bz2Ex.decompress!(compressed, threads: 4) == data
+      end
+    end
+
+    test "decompress inputs that expand past a single slice" do
+      data = :binary.copy("a", 5_000_000)
+      compressed = Bz2Ex.compress!(data)
+      assert byte_size(compressed) < 4096
+      assert Bz2Ex.decompress!(compressed) == data
+    end
+
+    test "decompress tiny inputs that expand to a whole block" do
+      data = :binary.copy("a", 10_000_000)
+
+      for block_size <- [1, 9] do
+        compressed = Bz2Ex.compress!(data, block_size: block_size)
+        assert byte_size(compressed) < 100
+        assert Bz2Ex.decompress!(compressed) == data
+        assert Bz2Ex.decompress!(compressed <> compressed) == data <> data
+      end
+    end
+  end
+
+  describe "parallel compression" do
+    setup do
+      [original: :crypto.strong_rand_bytes(50_000) |> :binary.copy(5)]
+    end
+
+    test "writes one stream per chunk", %{original: original} do
+      {:ok, compressed} = Bz2Ex.compress(original, block_size: 1, threads: 4)
+      {:ok, %{streams: streams}} = Bz2Ex.info(compressed)
+      assert length(streams) == 3
+      assert Enum.all?(streams, &match?(%{blocks: [_]}, &1))
+      {:ok, ^original} = Bz2Ex.decompress(compressed)
+    end
+
+    test "matches sequential output for a single chunk" do
+      data = String.duplicate("hello world ", 1000)
+      assert Bz2Ex.compress(data, threads: 4) == Bz2Ex.compress(data)
+    end
+
+    test "writes a single stream identical to sequential output", %{original: original} do
+      {:ok, compressed} = Bz2Ex.compress(original, block_size: 1, threads: 4, single_stream: true)
+      {:ok, %{streams: [%{blocks: [_, _ | _]}]}} = Bz2Ex.info(compressed)
+      assert {:ok, compressed} == Bz2Ex.compress(original, block_size: 1)
+      {:ok, ^original} = Bz2Ex.decompress(compressed, multi_stream: false)
+    end
+
+    test "handles empty binary" do
+      {:ok, compressed} = Bz2Ex.compress(<<>>, threads: 4)
+      {:ok, ""} = Bz2Ex.decompress(compressed)
+    end
+
+    test "raises on invalid threads" do
+      assert_raise ArgumentError, fn -> Bz2Ex.compress("data", threads: 0) end
+    end
   end
 
   describe "decompress/2" do
@@ -62,6 +177,542 @@ defmodule Bz2ExTest do
       {:ok, decompressed} = Bz2Ex.decompress(compressed, small: true)
       assert decompressed == original
     end
+
+    test "decodes concatenated streams" do
+      compressed = Bz2Ex.compress!("Hello, ") <> Bz2Ex.compress!("World!")
+      {:ok, decompressed} = Bz2Ex.decompress(compressed)
+      assert decompressed == "Hello, World!"
+    end
+
+    test "stops after the first stream when multi_stream is false" do
+      compressed = Bz2Ex.compress!("Hello, ") <> Bz2Ex.compress!("World!")
+      {:ok, decompressed} = Bz2Ex.decompress(compressed, multi_stream: false)
+      assert decompressed == "Hello, "
+    end
+
+    test "ignores trailing garbage after the last stream" do
+      compressed = Bz2Ex.compress!("Hello") <> "not bzip2"
+      {:ok, decompressed} = Bz2Ex.decompress(compressed)
+      assert decompressed == "Hello"
+    end
+
+    test "ignores trailing bytes too short to start another stream" do
+      compressed = Bz2Ex.compress!("Hello")
+
+      for garbage <- ["B", "BZ", "BZh"] do
+        {:ok, "Hello"} = Bz2Ex.decompress(compressed <> garbage)
+        {:ok, "Hello", ^garbage} = Bz2Ex.decompress_with_rest(compressed <> garbage, multi_stream: true)
+        {:ok, %{streams: 1}} = Bz2Ex.test(compressed <> garbage)
+      end
+    end
+
+    test "returns large outputs that behave like any other binary" do
+      original = :crypto.strong_rand_bytes(300_000)
+      compressed = Bz2Ex.compress!(original)
+      decompressed = Bz2Ex.decompress!(compressed)
+
+      assert decompressed == original
+      assert binary_part(decompressed, 1000, 10) == binary_part(original, 1000, 10)
+      assert :erlang.binary_to_term(:erlang.term_to_binary(decompressed)) == original
+      assert Task.await(Task.async(fn -> Bz2Ex.compress!(decompressed) end)) == compressed
+    end
+
+    test "returns error for truncated data" do
+      compressed = Bz2Ex.compress!(String.duplicate("abc", 1000))
+      truncated = binary_part(compressed, 0, byte_size(compressed) - 10)
+      {:error, :unexpected_eof} = Bz2Ex.decompress(truncated)
+    end
+  end
+
+  describe "output limits" do
+    setup do
+      [compressed: Bz2Ex.compress!(String.duplicate("a", 100_000))]
+    end
+
+    test "fails when output exceeds max_output_size", %{compressed: compressed} do
+      {:error, :output_limit_exceeded} = Bz2Ex.decompress(compressed, max_output_size: 99_999)
+    end
+
+    test "allows output exactly at max_output_size", %{compressed: compressed} do
+      {:ok, data} = Bz2Ex.decompress(compressed, max_output_size: 100_000)
+      assert byte_size(data) == 100_000
+    end
+
+    test "fails when output exceeds max_ratio", %{compressed: compressed} do
+      {:error, :output_limit_exceeded} = Bz2Ex.decompress(compressed, max_ratio: 10)
+    end
+
+    test "allows output within max_ratio", %{compressed: compressed} do
+      {:ok, _} = Bz2Ex.decompress(compressed, max_ratio: 100_000)
+    end
+
+    test "raises on invalid limits" do
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", max_output_size: -1) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", max_ratio: 0) end
+    end
+  end
+
+  describe "expected size" do
+    setup do
+      original = :binary.copy("expected ", 50_000) <> :crypto.strong_rand_bytes(50_000)
+      [original: original, compressed: Bz2Ex.compress!(original, block_size: 1)]
+    end
+
+    test "decodes the same output whatever the hint", %{original: original, compressed: compressed} do
+      size = byte_size(original)
+
+      for hint <- [size, 0, 1, size - 1, size + 1, size * 10], opts <- [[], [threads: 4]] do
+        {:ok, ^original} = Bz2Ex.decompress(compressed, [expected_size: hint] ++ opts)
+      end
+
+      {:ok, chunks} = Bz2Ex.decompress_iodata(compressed, expected_size: 1, chunk_size: 100_000)
+      assert IO.iodata_to_binary(chunks) == original
+    end
+
+    test "fails on a mismatch in strict mode", %{original: original, compressed: compressed} do
+      size = byte_size(original)
+
+      for opts <- [[], [threads: 4]] do
+        {:ok, ^original} = Bz2Ex.decompress(compressed, [expected_size: size, strict_size: true] ++ opts)
+
+        for hint <- [0, size - 1, size + 1] do
+          {:error, :size_mismatch} = Bz2Ex.decompress(compressed, [expected_size: hint, strict_size: true] ++ opts)
+        end
+      end
+
+      {:error, :size_mismatch} = Bz2Ex.decompress_iodata(compressed, expected_size: size - 1, strict_size: true)
+      ref = Bz2Ex.decompress_async(compressed, expected_size: size + 1, strict_size: true)
+      {:error, :size_mismatch} = Bz2Ex.await(ref)
+    end
+
+    test "does not allocate a huge hint up front", %{original: original, compressed: compressed} do
+      hint = Bitwise.bsl(1, 42)
+      {:ok, ^original} = Bz2Ex.decompress(compressed, expected_size: hint)
+      {:error, :size_mismatch} = Bz2Ex.decompress(compressed, expected_size: hint, strict_size: true)
+    end
+
+    test "reports the output limit before a mismatch", %{original: original, compressed: compressed} do
+      size = byte_size(original)
+      opts = [expected_size: size + 1, strict_size: true, max_output_size: size - 1]
+      {:error, :output_limit_exceeded} = Bz2Ex.decompress(compressed, opts)
+    end
+
+    test "raises on an invalid hint" do
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", expected_size: -1) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", expected_size: :unknown) end
+    end
+  end
+
+  describe "parallel decompression" do
+    setup do
+      chunks = for i <- 1..8, do: :binary.copy(<<i>>, 50_000) <> :crypto.strong_rand_bytes(1000)
+      compressed = chunks |> Enum.map(&Bz2Ex.compress!(&1, block_size: 1)) |> IO.iodata_to_binary()
+      [original: IO.iodata_to_binary(chunks), compressed: compressed]
+    end
+
+    test "matches sequential output", %{original: original, compressed: compressed} do
+      {:ok, ^original} = Bz2Ex.decompress(compressed, threads: 4)
+    end
+
+    test "decodes the blocks of a single stream", %{original: original} do
+      compressed = Bz2Ex.compress!(original, block_size: 1)
+      {:ok, %{streams: [%{blocks: [_, _ | _]}]}} = Bz2Ex.info(compressed)
+      {:ok, ^original} = Bz2Ex.decompress(compressed, threads: 4)
+      {:ok, ^original, "tail"} = Bz2Ex.decompress_with_rest(compressed <> "tail", threads: 4)
+    end
+
+    test "reports corrupted blocks like the sequential decoder", %{original: original} do
+      compressed = Bz2Ex.compress!(original, block_size: 1)
+      mid = div(byte_size(compressed), 2)
+      <<head::binary-size(mid), byte, tail::binary>> = compressed
+      corrupted = <<head::binary, Bitwise.bxor(byte, 0xFF), tail::binary>>
+      assert Bz2Ex.decompress(corrupted, threads: 4) == Bz2Ex.decompress(corrupted)
+      assert {:error, _} = Bz2Ex.decompress(corrupted, threads: 4)
+    end
+
+    test "ignores trailing garbage", %{original: original, compressed: compressed} do
+      {:ok, ^original} = Bz2Ex.decompress(compressed <> "not bzip2", threads: 4)
+      {:ok, ^original, "tail"} = Bz2Ex.decompress_with_rest(compressed <> "tail", multi_stream: true, threads: 4)
+      {:ok, ^original, "B"} = Bz2Ex.decompress_with_rest(compressed <> "B", multi_stream: true, threads: 4)
+    end
+
+    test "returns error for truncated data", %{compressed: compressed} do
+      truncated = binary_part(compressed, 0, byte_size(compressed) - 10)
+      {:error, :unexpected_eof} = Bz2Ex.decompress(truncated, threads: 4)
+    end
+
+    test "enforces output limits", %{original: original, compressed: compressed} do
+      {:error, :output_limit_exceeded} =
+        Bz2Ex.decompress(compressed, threads: 4, max_output_size: byte_size(original) - 1)
+    end
+
+    test "raises on invalid threads" do
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", threads: 0) end
+    end
+  end
+
+  describe "scheduling" do
+    test "round-trips inputs that run on dirty schedulers" do
+      for size <- [65_535, 65_537, 2_000_000, 9_000_000] do
+        original = :crypto.strong_rand_bytes(div(size, 2)) <> :binary.copy("a", size - div(size, 2))
+        compressed = Bz2Ex.compress!(original, block_size: 1)
+        assert Bz2Ex.decompress!(compressed) == original
+        assert {:ok, ^original, "tail"} = Bz2Ex.decompress_with_rest(compressed <> "tail")
+      end
+    end
+
+    test "streams large inputs" do
+      original = :crypto.strong_rand_bytes(3_000_000)
+      {:ok, stream} = Bz2Ex.Stream.compress_init(block_size: 1)
+      {:ok, chunk, stream} = Bz2Ex.Stream.compress(stream, original)
+      {:ok, final} = Bz2Ex.Stream.compress_finish(stream)
+      compressed = chunk <> final
+      assert compressed == Bz2Ex.compress!(original, block_size: 1)
+
+      {:ok, stream} = Bz2Ex.Stream.decompress_init()
+      {:ok, ^original, :finished, "tail", _} = Bz2Ex.Stream.decompress_with_rest(stream, compressed <> "tail")
+    end
+  end
+
+  describe "async operations" do
+    test "compress_async replies with the compressed data" do
+      original = :crypto.strong_rand_bytes(100_000)
+      ref = Bz2Ex.compress_async(original)
+      assert is_reference(ref)
+      assert_receive {^ref, {:ok, compressed}}, 5000
+      assert compressed == Bz2Ex.compress!(original)
+    end
+
+    test "decompress_async replies with the decompressed data" do
+      compressed = Bz2Ex.compress!("Hello, ") <> Bz2Ex.compress!("World!")
+      {:ok, "Hello, World!"} = compressed |> Bz2Ex.decompress_async() |> Bz2Ex.await()
+    end
+
+    test "replies with errors" do
+      {:error, :data_error_magic} = <<1, 2, 3, 4, 5>> |> Bz2Ex.decompress_async() |> Bz2Ex.await()
+
+      {:error, :output_limit_exceeded} =
+        String.duplicate("a", 10_000)
+        |> Bz2Ex.compress!()
+        |> Bz2Ex.decompress_async(max_output_size: 100)
+        |> Bz2Ex.await()
+    end
+
+    test "runs several jobs at once" do
+      inputs = for i <- 1..8, do: :binary.copy(<<i>>, 50_000)
+      refs = Enum.map(inputs, &Bz2Ex.compress_async(&1, threads: 2))
+
+      for {ref, input} <- Enum.zip(refs, inputs) do
+        {:ok, compressed} = Bz2Ex.await(ref)
+        {:ok, ^input} = Bz2Ex.decompress(compressed)
+      end
+    end
+
+    test "await exits on timeout" do
+      assert catch_exit(Bz2Ex.await(make_ref(), 10))
+    end
+  end
+
+  describe "progress reporting" do
+    test "sends progress messages while compressing and decompressing" do
+      data = :crypto.strong_rand_bytes(3_000_000)
+
+      ref = Bz2Ex.compress_async(data, progress: self(), progress_interval: 1_000_000)
+      {:ok, compressed} = Bz2Ex.await(ref)
+      assert_received {:bz2_progress, ^ref, bytes_in, bytes_out}
+      assert bytes_in >= 1_000_000 and bytes_in <= byte_size(data)
+      assert bytes_out <= byte_size(compressed)
+
+      ref = Bz2Ex.decompress_async(compressed, progress: self(), progress_interval: 1_000_000, threads: 2)
+      {:ok, ^data} = Bz2Ex.await(ref)
+      assert_received {:bz2_progress, ^ref, _, _}
+    end
+
+    test "sends nothing without a pid" do
+      ref = Bz2Ex.compress_async(:crypto.strong_rand_bytes(2_000_000), progress_interval: 1)
+      {:ok, _} = Bz2Ex.await(ref)
+      refute_received {:bz2_progress, ^ref, _, _}
+    end
+
+    test "raises on invalid options" do
+      assert_raise ArgumentError, fn -> Bz2Ex.compress_async("data", progress: :me) end
+      assert_raise ArgumentError, fn -> Bz2Ex.compress_async("data", progress: self(), progress_interval: 0) end
+    end
+  end
+
+  describe "cancellation" do
+    test "a cancelled handle stops async jobs" do
+      handle = Bz2Ex.CancelHandle.new()
+      refute Bz2Ex.CancelHandle.cancelled?(handle)
+      :ok = Bz2Ex.CancelHandle.cancel(handle)
+      assert Bz2Ex.CancelHandle.cancelled?(handle)
+
+      data = :binary.copy("abc", 1_000_000)
+      {:error, :cancelled} = data |> Bz2Ex.compress_async(cancel: handle) |> Bz2Ex.await()
+      {:error, :cancelled} = data |> Bz2Ex.compress_async(cancel: handle, threads: 4) |> Bz2Ex.await()
+
+      compressed = Bz2Ex.compress!(data)
+      {:error, :cancelled} = compressed |> Bz2Ex.decompress_async(cancel: handle) |> Bz2Ex.await()
+      {:error, :cancelled} = compressed |> Bz2Ex.decompress_async(cancel: handle, threads: 4) |> Bz2Ex.await()
+    end
+
+    test "jobs with an untouched handle complete" do
+      handle = Bz2Ex.CancelHandle.new()
+      {:ok, compressed} = "payload" |> Bz2Ex.compress_async(cancel: handle) |> Bz2Ex.await()
+      {:ok, "payload"} = compressed |> Bz2Ex.decompress_async(cancel: handle) |> Bz2Ex.await()
+    end
+  end
+
+  describe "input ranges" do
+    test "compresses only the selected bytes" do
+      data = :crypto.strong_rand_bytes(20_000)
+      part = binary_part(data, 5000, 10_000)
+
+      assert Bz2Ex.compress!(data, offset: 5000, length: 10_000) == Bz2Ex.compress!(part)
+      assert Bz2Ex.compress!(data, offset: 5000) == Bz2Ex.compress!(binary_part(data, 5000, 15_000))
+      assert Bz2Ex.compress!(data, offset: 20_000) == Bz2Ex.compress!("")
+
+      iolist = [binary_part(data, 0, 7000), ?x, [binary_part(data, 7000, 13_000)]]
+      flat = IO.iodata_to_binary(iolist)
+
+      for {offset, length} <- [{0, 7000}, {6999, 3}, {7000, 1}, {7001, 100}, {100, 19_000}] do
+        expected = Bz2Ex.compress!(binary_part(flat, offset, length))
+        assert Bz2Ex.compress!(iolist, offset: offset, length: length) == expected
+      end
+    end
+
+    test "decompresses only the selected bytes" do
+      compressed = Bz2Ex.compress!("payload")
+      container = "header" <> compressed <> "trailer"
+      opts = [offset: 6, length: byte_size(compressed)]
+
+      {:ok, "payload"} = Bz2Ex.decompress(container, opts)
+      {:ok, "payload", ""} = Bz2Ex.decompress_with_rest(container, opts)
+      {:ok, "payload", "trailer"} = Bz2Ex.decompress_with_rest(container, offset: 6)
+      {:ok, ["payload"]} = Bz2Ex.decompress_iodata(container, opts)
+      {:error, :unexpected_eof} = Bz2Ex.decompress(container, offset: 6, length: byte_size(compressed) - 1)
+    end
+
+    test "works with async operations" do
+      data = :crypto.strong_rand_bytes(10_000)
+      {:ok, compressed} = Bz2Ex.await(Bz2Ex.compress_async(data, offset: 1000, length: 2000))
+      assert Bz2Ex.decompress!(compressed) == binary_part(data, 1000, 2000)
+
+      ref = Bz2Ex.decompress_async("xx" <> compressed, offset: 2)
+      {:ok, decompressed} = Bz2Ex.await(ref)
+      assert decompressed == binary_part(data, 1000, 2000)
+    end
+
+    test "raises on ranges outside the input" do
+      assert_raise ArgumentError, fn -> Bz2Ex.compress("data", offset: 5) end
+      assert_raise ArgumentError, fn -> Bz2Ex.compress("data", offset: 2, length: 3) end
+      assert_raise ArgumentError, fn -> Bz2Ex.compress(["da", "ta"], offset: 4, length: 1) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", offset: 5) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", length: 5) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress_async("data", offset: 5) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress("data", offset: -1) end
+    end
+  end
+
+  describe "decompress_with_rest/2" do
+    test "returns the bytes after the stream" do
+      compressed = Bz2Ex.compress!("payload")
+      {:ok, "payload", "trailer"} = Bz2Ex.decompress_with_rest(compressed <> "trailer")
+    end
+
+    test "returns empty rest when nothing follows" do
+      compressed = Bz2Ex.compress!("payload")
+      {:ok, "payload", ""} = Bz2Ex.decompress_with_rest(compressed)
+    end
+
+    test "stops at the first stream by default" do
+      second = Bz2Ex.compress!("second")
+      compressed = Bz2Ex.compress!("first") <> second
+      {:ok, "first", ^second} = Bz2Ex.decompress_with_rest(compressed)
+    end
+
+    test "skips following streams when multi_stream is true" do
+      compressed = Bz2Ex.compress!("first") <> Bz2Ex.compress!("second") <> "tail"
+      {:ok, "firstsecond", "tail"} = Bz2Ex.decompress_with_rest(compressed, multi_stream: true)
+    end
+
+    test "returns error for invalid data" do
+      {:error, :data_error_magic} = Bz2Ex.decompress_with_rest(<<1, 2, 3, 4, 5>>)
+    end
+  end
+
+  describe "decompress_iodata/2" do
+    test "returns chunks of chunk_size bytes" do
+      original = :crypto.strong_rand_bytes(250_000)
+      compressed = Bz2Ex.compress!(original)
+
+      {:ok, chunks} = Bz2Ex.decompress_iodata(compressed, chunk_size: 100_000)
+      assert Enum.map(chunks, &byte_size/1) == [100_000, 100_000, 50_000]
+      assert IO.iodata_to_binary(chunks) == original
+
+      {:ok, [^original]} = Bz2Ex.decompress_iodata(compressed)
+    end
+
+    test "matches decompress/2 with any options" do
+      original = :binary.copy("chunked output ", 200_000)
+      compressed = Bz2Ex.compress!(original, threads: 4)
+
+      for opts <- [[], [threads: 4], [small: true], [multi_stream: false]] do
+        {:ok, expected} = Bz2Ex.decompress(compressed, opts)
+        {:ok, chunks} = Bz2Ex.decompress_iodata(compressed, [chunk_size: 65_536] ++ opts)
+        assert IO.iodata_to_binary(chunks) == expected
+        assert Enum.all?(Enum.drop(chunks, -1), &(byte_size(&1) == 65_536))
+      end
+    end
+
+    test "returns an empty list for empty output" do
+      {:ok, []} = Bz2Ex.decompress_iodata(Bz2Ex.compress!(""))
+    end
+
+    test "enforces output limits" do
+      compressed = Bz2Ex.compress!(:binary.copy("a", 300_000))
+      {:ok, _} = Bz2Ex.decompress_iodata(compressed, chunk_size: 1000, max_output_size: 300_000)
+      {:error, :output_limit_exceeded} = Bz2Ex.decompress_iodata(compressed, chunk_size: 1000, max_output_size: 299_999)
+    end
+
+    test "returns errors and raises on invalid chunk_size" do
+      {:error, :data_error_magic} = Bz2Ex.decompress_iodata(<<1, 2, 3, 4, 5>>)
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress_iodata(Bz2Ex.compress!("x"), chunk_size: 0) end
+    end
+  end
+
+  describe "compress_many/2 and decompress_many/2" do
+    test "round trip every element in order" do
+      inputs = ["", "hello", :binary.copy("batch ", 50_000), :crypto.strong_rand_bytes(300_000)]
+
+      compressed = for {:ok, data} <- Bz2Ex.compress_many(inputs), do: data
+      assert compressed == Enum.map(inputs, &Bz2Ex.compress!/1)
+      assert Bz2Ex.decompress_many(compressed) == Enum.map(inputs, &{:ok, &1})
+    end
+
+    test "threads give the same results" do
+      inputs = for i <- 1..20, do: :crypto.strong_rand_bytes(5_000 * i)
+
+      results = Bz2Ex.compress_many(inputs)
+      assert Bz2Ex.compress_many(inputs, threads: 4) == results
+
+      compressed = for {:ok, data} <- results, do: data
+      assert Bz2Ex.decompress_many(compressed, threads: 4) == Enum.map(inputs, &{:ok, &1})
+    end
+
+    test "report errors per element" do
+      good = Bz2Ex.compress!(:binary.copy("a", 100_000))
+      inputs = [good, <<1, 2, 3, 4, 5>>, good, binary_part(good, 0, byte_size(good) - 10)]
+
+      for threads <- [1, 4] do
+        assert [
+                 {:ok, _},
+                 {:error, :data_error_magic},
+                 {:ok, _},
+                 {:error, :unexpected_eof}
+               ] = Bz2Ex.decompress_many(inputs, threads: threads)
+
+        assert [{:error, :output_limit_exceeded}, {:ok, "small"}] =
+                 Bz2Ex.decompress_many([good, Bz2Ex.compress!("small")], max_output_size: 1000, threads: threads)
+      end
+    end
+
+    test "decompress small batches that expand to whole blocks" do
+      data = :binary.copy("a", 10_000_000)
+      blocks = for block_size <- [1, 9], do: Bz2Ex.compress!(data, block_size: block_size)
+      records = for i <- 1..20, do: Bz2Ex.compress!("record #{i}")
+      inputs = records ++ blocks ++ records
+      assert inputs |> Enum.map(&byte_size/1) |> Enum.sum() < 4096
+
+      assert Bz2Ex.decompress_many(inputs) == Enum.map(inputs, &Bz2Ex.decompress/1)
+      assert Bz2Ex.decompress_many(records) == Enum.map(records, &Bz2Ex.decompress/1)
+    end
+
+    test "handle empty lists and invalid arguments" do
+      assert Bz2Ex.compress_many([]) == []
+      assert Bz2Ex.decompress_many([]) == []
+      assert_raise ArgumentError, fn -> Bz2Ex.compress_many(["ok", :not_binary]) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress_many(["ok", :not_binary]) end
+      assert_raise ArgumentError, fn -> Bz2Ex.compress_many(["ok"], block_size: 10) end
+      assert_raise ArgumentError, fn -> Bz2Ex.decompress_many(["ok"], threads: 0) end
+    end
+  end
+
+  describe "test/2" do
+    test "reports size, streams and blocks" do
+      original = :crypto.strong_rand_bytes(250_000)
+      compressed = Bz2Ex.compress!(original, block_size: 1)
+
+      {:ok, report} = Bz2Ex.test(compressed)
+      assert report.uncompressed_size == 250_000
+      assert report.streams == 1
+      assert report.blocks == 3
+    end
+
+    test "counts concatenated streams" do
+      compressed = Bz2Ex.compress!("first") <> Bz2Ex.compress!("second")
+      {:ok, %{uncompressed_size: 11, streams: 2, blocks: 2}} = Bz2Ex.test(compressed)
+    end
+
+    test "reports empty streams" do
+      {:ok, %{uncompressed_size: 0, streams: 1, blocks: 0}} = Bz2Ex.test(Bz2Ex.compress!(""))
+    end
+
+    test "detects corrupted data" do
+      compressed = Bz2Ex.compress!(String.duplicate("corrupt me ", 1000))
+      offset = div(byte_size(compressed), 2)
+      <<head::binary-size(offset), byte, tail::binary>> = compressed
+      corrupted = <<head::binary, Bitwise.bxor(byte, 0x10), tail::binary>>
+
+      {:error, :data_error} = Bz2Ex.test(corrupted)
+    end
+
+    test "returns error for invalid data" do
+      {:error, :data_error_magic} = Bz2Ex.test(<<1, 2, 3, 4, 5>>)
+    end
+  end
+
+  describe "info/1" do
+    test "reports the block size digit" do
+      {:ok, %{block_size: 3}} = Bz2Ex.info(Bz2Ex.compress!("data", block_size: 3))
+    end
+
+    test "lists blocks with offsets, lengths and CRCs" do
+      original = :crypto.strong_rand_bytes(250_000)
+      compressed = Bz2Ex.compress!(original, block_size: 1)
+
+      {:ok, %{streams: [stream]}} = Bz2Ex.info(compressed)
+      assert stream.offset == 0
+      assert stream.length == byte_size(compressed)
+      assert [first, second, third] = stream.blocks
+      assert first.bit_offset == 32
+      assert second.bit_offset == first.bit_offset + first.bit_length
+      assert third.bit_offset == second.bit_offset + second.bit_length
+
+      combined =
+        Enum.reduce(stream.blocks, 0, fn %{crc: crc}, acc ->
+          rotated = Bitwise.band(Bitwise.bor(Bitwise.bsl(acc, 1), Bitwise.bsr(acc, 31)), 0xFFFFFFFF)
+          Bitwise.bxor(rotated, crc)
+        end)
+
+      assert stream.crc == combined
+    end
+
+    test "lists concatenated streams" do
+      first = Bz2Ex.compress!("first", block_size: 1)
+      second = Bz2Ex.compress!("second", block_size: 9)
+
+      {:ok, %{block_size: 1, streams: [s1, s2]}} = Bz2Ex.info(first <> second)
+      assert s1.block_size == 1
+      assert s2.block_size == 9
+      assert s2.offset == byte_size(first)
+      assert [%{bit_offset: bit_offset}] = s2.blocks
+      assert bit_offset == byte_size(first) * 8 + 32
+    end
+
+    test "returns error for invalid data" do
+      {:error, :data_error_magic} = Bz2Ex.info(<<1, 2, 3, 4, 5>>)
+    end
   end
 
   describe "bang variants" do